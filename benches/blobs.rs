@@ -0,0 +1,66 @@
+//! Measures the cost of reading the data of a large blob through each of the available interfaces.
+//!
+//! Run with `cargo bench --features test-utils`
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use jupiter::{
+    shares::{bufs_eq, Blob, BlobRef, Share},
+    test_utils::{sparse_shares, TEST_NAMESPACE},
+};
+use prost::bytes::Buf;
+
+/// The size of the benchmarked blob
+const BLOB_SIZE: usize = 2 * 1024 * 1024;
+
+fn blob_benchmarks(c: &mut Criterion) {
+    let data: Vec<u8> = (0..BLOB_SIZE).map(|i| i as u8).collect();
+    let blob = Blob(
+        sparse_shares(TEST_NAMESPACE, &data)
+            .into_iter()
+            .map(|share| Share::new(share.into()))
+            .collect(),
+    );
+
+    let mut group = c.benchmark_group("blob_2mb");
+    group.throughput(Throughput::Bytes(BLOB_SIZE as u64));
+    group.bench_function("iterate_bytes", |b| {
+        b.iter(|| {
+            blob.clone()
+                .into_iter()
+                .fold(0u8, |acc, byte| acc.wrapping_add(byte))
+        })
+    });
+    group.bench_function("iterate_chunks", |b| {
+        b.iter(|| {
+            let mut data = BlobRef::with(&blob.0).data();
+            let mut total = 0;
+            while data.has_remaining() {
+                let len = black_box(data.chunk()).len();
+                total += len;
+                data.advance(len);
+            }
+            total
+        })
+    });
+    group.bench_function("copy_to_bytes_per_share", |b| {
+        b.iter(|| {
+            let mut data = blob.clone().into_iter();
+            while data.has_remaining() {
+                let len = data.chunk().len();
+                black_box(data.copy_to_bytes(len));
+            }
+        })
+    });
+    group.bench_function("to_bytes", |b| b.iter(|| blob.to_bytes()));
+    group.bench_function("compare", |b| {
+        b.iter(|| {
+            assert!(bufs_eq(
+                BlobRef::with(&blob.0).data(),
+                BlobRef::with(&blob.0).data()
+            ))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, blob_benchmarks);
+criterion_main!(benches);
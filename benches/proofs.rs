@@ -0,0 +1,88 @@
+//! Reports the size of Jupiter's proofs and the time taken to verify them for several square sizes.
+//!
+//! Run with `cargo bench --features test-utils`
+use std::collections::BTreeSet;
+
+use borsh::BorshSerialize;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use jupiter::{
+    test_utils::{BlockBuilder, TestBlob, TEST_NAMESPACE},
+    verifier::{
+        compact::CompactEncode,
+        proofs::{CompletenessProof, CorrectnessProof, EtxProof, RelevantRowProof},
+        CelestiaVerifier, RollupParams,
+    },
+};
+use sovereign_sdk::da::DaVerifier;
+
+/// Widths of the original data square to benchmark
+const SQUARE_WIDTHS: &[usize] = &[4, 8, 16, 32, 64];
+/// Bytes of blob data which fit in a sparse share
+const SHARE_DATA_LEN: usize = 500;
+
+fn proof_benchmarks(c: &mut Criterion) {
+    let verifier = CelestiaVerifier::new(RollupParams {
+        namespaces: BTreeSet::from([TEST_NAMESPACE]),
+    });
+    let mut group = c.benchmark_group("proofs");
+    for &width in SQUARE_WIDTHS {
+        // Fill roughly half of the square with two rollup blobs
+        let blob_len = width * width * SHARE_DATA_LEN / 4;
+        let (block, txs) = BlockBuilder::new(width)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![1; blob_len]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![2; blob_len]))
+            .build(TEST_NAMESPACE);
+
+        let inclusion_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        let completeness_proof = CompletenessProof::from_filtered_block(&block).0;
+        report_sizes(width, &inclusion_proof, &completeness_proof);
+
+        group.bench_with_input(BenchmarkId::new("verify", width), &width, |b, _| {
+            b.iter_batched(
+                || (inclusion_proof.clone(), completeness_proof.clone()),
+                |(inclusion_proof, completeness_proof)| {
+                    verifier
+                        .verify_relevant_tx_list(
+                            &block.header,
+                            &txs,
+                            inclusion_proof,
+                            completeness_proof,
+                        )
+                        .expect("proofs must be valid")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        let encoded_inclusion = inclusion_proof.to_compact_bytes();
+        let encoded_completeness = completeness_proof.to_compact_bytes();
+        group.bench_with_input(BenchmarkId::new("decode_compact", width), &width, |b, _| {
+            b.iter(|| {
+                (
+                    EtxProof::from_compact_bytes(&encoded_inclusion).unwrap(),
+                    Vec::<RelevantRowProof>::from_compact_bytes(&encoded_completeness).unwrap(),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn report_sizes(
+    width: usize,
+    inclusion_proof: &EtxProof,
+    completeness_proof: &Vec<RelevantRowProof>,
+) {
+    println!(
+        "square width {:>3}: inclusion proof {:>8} bytes borsh / {:>8} bytes compact, \
+         completeness proof {:>8} bytes borsh / {:>8} bytes compact",
+        width,
+        inclusion_proof.try_to_vec().unwrap().len(),
+        inclusion_proof.to_compact_bytes().len(),
+        completeness_proof.try_to_vec().unwrap().len(),
+        completeness_proof.to_compact_bytes().len(),
+    );
+}
+
+criterion_group!(benches, proof_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+//! Splits arbitrary bytes into shares and checks that every way of reading their blobs agrees.
+//!
+//! Run with `cargo fuzz run blob_iterators` from the repository root
+#![no_main]
+
+use jupiter::shares::{Blob, NamespaceGroup, Share};
+use libfuzzer_sys::fuzz_target;
+
+const SHARE_SIZE: usize = 512;
+
+fuzz_target!(|input: (Vec<u8>, bool)| {
+    let (data, compact) = input;
+    let shares: Vec<Share> = data
+        .chunks_exact(SHARE_SIZE)
+        .filter_map(|share| Share::try_new(share.to_vec().into()).ok())
+        .collect();
+    let group = if compact {
+        NamespaceGroup::Compact(shares)
+    } else {
+        NamespaceGroup::Sparse(shares)
+    };
+    for blob in group.blobs() {
+        let data = match blob.try_data() {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let read: Vec<u8> = data.collect();
+        // A blob is only readable if its shares hold all of the data of its sequence
+        assert_eq!(read.len() as u64, blob.0[0].sequence_length().unwrap());
+        assert_eq!(&blob.to_bytes()[..], &read[..]);
+        let owned = Blob::from(blob);
+        assert_eq!(owned.into_iter().collect::<Vec<u8>>(), read);
+    }
+});
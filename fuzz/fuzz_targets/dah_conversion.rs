@@ -0,0 +1,31 @@
+//! Decodes arbitrary roots into a [`DataAvailabilityHeader`], which must fail with an error rather than panic.
+//! Roots are generated both as arbitrary strings and as base64 encodings of arbitrary bytes, so that roots
+//! of the wrong length are reached as well as invalid base64.
+//!
+//! Run with `cargo fuzz run dah_conversion` from the repository root
+#![no_main]
+
+use jupiter::{DataAvailabilityHeader, MarshalledDataAvailabilityHeader};
+use libfuzzer_sys::fuzz_target;
+
+fn check(row_roots: Vec<String>, column_roots: Vec<String>) {
+    let marshalled = MarshalledDataAvailabilityHeader {
+        row_roots,
+        column_roots,
+    };
+    if let Ok(dah) = DataAvailabilityHeader::try_from(marshalled) {
+        let _ = dah.validate_structure();
+    }
+}
+
+fuzz_target!(|input: (Vec<Vec<u8>>, Vec<String>)| {
+    let (mut raw_roots, string_roots) = input;
+    let column_roots = raw_roots
+        .split_off(raw_roots.len() / 2)
+        .iter()
+        .map(base64::encode)
+        .collect();
+    let row_roots = raw_roots.iter().map(base64::encode).collect();
+    check(row_roots, column_roots);
+    check(string_roots.clone(), string_roots);
+});
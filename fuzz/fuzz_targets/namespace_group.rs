@@ -0,0 +1,17 @@
+//! Parses arbitrary base64 as a group of shares, and reads every blob in any group which is accepted.
+//!
+//! Run with `cargo fuzz run namespace_group` from the repository root
+#![no_main]
+
+use jupiter::shares::NamespaceGroup;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|b64: &str| {
+    if let Ok(group) = NamespaceGroup::from_b64(b64) {
+        for blob in group.blobs() {
+            if let Ok(data) = blob.try_data() {
+                assert_eq!(data.count(), blob.to_bytes().len());
+            }
+        }
+    }
+});
@@ -0,0 +1,26 @@
+//! Parses arbitrary shares in the pfb namespace, which must fail with an error rather than panic. The
+//! namespace of each share is overwritten so that the fuzzer spends its time on the share contents.
+//!
+//! Run with `cargo fuzz run parse_pfb_namespace` from the repository root
+#![no_main]
+
+use jupiter::{
+    parse_pfb_namespace,
+    shares::{NamespaceGroup, Share},
+    verifier::PFB_NAMESPACE,
+};
+use libfuzzer_sys::fuzz_target;
+
+const SHARE_SIZE: usize = 512;
+
+fuzz_target!(|data: &[u8]| {
+    let shares: Vec<Share> = data
+        .chunks_exact(SHARE_SIZE)
+        .filter_map(|share| {
+            let mut share = share.to_vec();
+            share[..8].copy_from_slice(&PFB_NAMESPACE.0);
+            Share::try_new(share.into()).ok()
+        })
+        .collect();
+    let _ = parse_pfb_namespace(NamespaceGroup::Compact(shares));
+});
@@ -0,0 +1,18 @@
+//! Deserializes arbitrary json strings as shares, and reads the metadata of any share which is accepted.
+//!
+//! Run with `cargo fuzz run share_deserialize` from the repository root
+#![no_main]
+
+use jupiter::shares::Share;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (&str, usize)| {
+    let (encoded, idx) = input;
+    let json = serde_json::to_string(encoded).expect("strings always serialize");
+    if let Ok(share) = serde_json::from_str::<Share>(&json) {
+        let _ = share.namespace();
+        let _ = share.data_ref();
+        let _ = share.sequence_length();
+        let _ = share.is_valid_tx_start(idx);
+    }
+});
@@ -0,0 +1,18 @@
+//! Checks arbitrary offsets against an arbitrary share in the pfb namespace, which must never panic.
+//!
+//! Run with `cargo fuzz run tx_start` from the repository root
+#![no_main]
+
+use jupiter::{shares::Share, verifier::PFB_NAMESPACE};
+use libfuzzer_sys::fuzz_target;
+
+const SHARE_SIZE: usize = 512;
+
+fuzz_target!(|input: (Vec<u8>, usize)| {
+    let (mut share, idx) = input;
+    share.resize(SHARE_SIZE, 0);
+    share[..8].copy_from_slice(&PFB_NAMESPACE.0);
+    if let Ok(share) = Share::try_new(share.into()) {
+        let _ = share.is_valid_tx_start(idx);
+    }
+});
@@ -0,0 +1,15 @@
+//! Reads and skips varints in arbitrary bytes, which must agree on the length of the varint.
+//!
+//! Run with `cargo fuzz run varint` from the repository root
+#![no_main]
+
+use jupiter::shares::{read_varint, skip_varint};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let read = read_varint(data);
+    let skipped = skip_varint(data);
+    if let (Ok((_, read_len)), Ok(skipped_len)) = (read, skipped) {
+        assert_eq!(read_len, skipped_len);
+    }
+});
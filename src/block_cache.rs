@@ -0,0 +1,572 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::ensure;
+use borsh::{BorshDeserialize, BorshSerialize};
+use nmt_rs::{NamespaceId, NamespacedHash};
+use sovereign_sdk::services::da::SlotData;
+use tendermint::crypto::{default::Sha256, Sha256 as _};
+use tracing::{debug, warn};
+
+use crate::{
+    da_service::check_correctness_proof,
+    parse_pfb_namespace,
+    shares::{NamespaceGroup, Share},
+    types::{FilteredCelestiaBlock, RelevantPfb, Row},
+    utils::BoxError,
+    verifier::PFB_NAMESPACE,
+    DataAvailabilityHeader,
+};
+
+const CACHE_FILE_EXTENSION: &str = "borsh";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+
+/// Configuration for the on-disk block cache
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct BlockCacheConfig {
+    /// The directory in which cached blocks are stored
+    pub path: PathBuf,
+    /// The maximum total size of the cached blocks, in bytes. The least recently used blocks are evicted
+    /// once the cache grows past this size.
+    #[serde(default = "default_max_cache_size")]
+    pub max_size_bytes: u64,
+}
+
+fn default_max_cache_size() -> u64 {
+    1024 * 1024 * 1024 * 10 // 10 GB
+}
+
+/// A persistent cache of [`FilteredCelestiaBlock`]s filtered for a set of rollup namespaces, keyed by height and
+/// header hash. Each block is stored borsh-serialized in its own file, whose name also identifies the namespaces,
+/// and is checked against its header before being returned. Blocks cached for other namespaces are ignored.
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    dir: PathBuf,
+    max_size_bytes: u64,
+    namespaces: Arc<BTreeSet<NamespaceId>>,
+    /// Identifies the namespaces in the names of the cache files
+    namespaces_key: String,
+    index: Arc<Mutex<CacheIndex>>,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: BTreeMap<u64, CacheEntry>,
+    total_size: u64,
+    /// Incremented on every access, to track which entries were used least recently
+    clock: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    hash: [u8; 32],
+    size: u64,
+    last_used: u64,
+}
+
+impl CacheIndex {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, height: u64) -> Option<CacheEntry> {
+        let entry = self.entries.remove(&height)?;
+        self.total_size -= entry.size;
+        Some(entry)
+    }
+}
+
+impl BlockCache {
+    /// Opens the cache of blocks filtered for `namespaces` in the configured directory, creating the directory if
+    /// necessary. Blocks cached by a previous run are indexed in the order in which their files were last modified.
+    pub fn open(
+        config: &BlockCacheConfig,
+        namespaces: &BTreeSet<NamespaceId>,
+    ) -> Result<Self, BoxError> {
+        let namespaces_key = namespaces_key(namespaces);
+        fs::create_dir_all(&config.path)?;
+        let mut found = Vec::new();
+        for file in fs::read_dir(&config.path)? {
+            let file = file?;
+            let path = file.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == TEMP_FILE_EXTENSION)
+            {
+                // Left behind by an interrupted write
+                fs::remove_file(&path)?;
+                continue;
+            }
+            match parse_file_name(&path) {
+                Some((height, hash, key)) if key == namespaces_key => {
+                    let metadata = file.metadata()?;
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    found.push((modified, height, hash, metadata.len()));
+                }
+                _ => {}
+            }
+        }
+        found.sort();
+
+        let cache = Self {
+            dir: config.path.clone(),
+            max_size_bytes: config.max_size_bytes,
+            namespaces: Arc::new(namespaces.clone()),
+            namespaces_key,
+            index: Default::default(),
+        };
+        let mut index = cache.index.lock().unwrap();
+        for (_, height, hash, size) in found {
+            // Keep only the most recently written block at each height
+            if let Some(stale) = index.remove(height) {
+                remove_file(&cache.entry_path(height, &stale.hash));
+            }
+            let last_used = index.tick();
+            index.entries.insert(
+                height,
+                CacheEntry {
+                    hash,
+                    size,
+                    last_used,
+                },
+            );
+            index.total_size += size;
+        }
+        for path in cache.evict(&mut index) {
+            remove_file(&path);
+        }
+        drop(index);
+        Ok(cache)
+    }
+
+    /// The namespaces for which the cached blocks were filtered
+    pub fn namespaces(&self) -> &BTreeSet<NamespaceId> {
+        &self.namespaces
+    }
+
+    /// Returns the cached block at `height`, if there is one. Entries which can't be read or which fail
+    /// verification are removed from the cache. The file is read and checked on the blocking thread pool,
+    /// without holding the index lock.
+    pub async fn get(&self, height: u64) -> Option<FilteredCelestiaBlock> {
+        let entry = *self.index.lock().unwrap().entries.get(&height)?;
+        let path = self.entry_path(height, &entry.hash);
+        let namespaces = self.namespaces.clone();
+        let read_path = path.clone();
+        let block = tokio::task::spawn_blocking(move || -> Result<_, BoxError> {
+            let block = FilteredCelestiaBlock::try_from_slice(&fs::read(&read_path)?)?;
+            verify_cached_block(&block, height, &entry.hash, &namespaces)?;
+            Ok(block)
+        })
+        .await
+        .map_err(BoxError::from)
+        .and_then(|result| result);
+
+        // The entry may have been replaced or evicted while its file was read
+        let is_current = {
+            let mut index = self.index.lock().unwrap();
+            let last_used = index.tick();
+            let is_current = index
+                .entries
+                .get(&height)
+                .map_or(false, |current| current.hash == entry.hash);
+            match (is_current, &block) {
+                (true, Ok(_)) => {
+                    if let Some(current) = index.entries.get_mut(&height) {
+                        current.last_used = last_used;
+                    }
+                }
+                (true, Err(_)) => {
+                    index.remove(height);
+                }
+                (false, _) => {}
+            }
+            is_current
+        };
+        match block {
+            Ok(block) => {
+                debug!("Loaded block at height={} from the cache", height);
+                Some(block)
+            }
+            Err(e) => {
+                warn!("Discarding cached block at height={}: {}", height, e);
+                if is_current {
+                    remove_files(vec![path]).await;
+                }
+                None
+            }
+        }
+    }
+
+    /// Stores the block at `height`, evicting the least recently used blocks if the cache grows too large.
+    /// Blocks which are larger than the whole cache are not stored. Files are written and removed on the
+    /// blocking thread pool, without holding the index lock.
+    pub async fn insert(&self, height: u64, block: &FilteredCelestiaBlock) -> Result<(), BoxError> {
+        let bytes = block.try_to_vec()?;
+        let size = bytes.len() as u64;
+        if size > self.max_size_bytes {
+            debug!(
+                "Not caching block at height={}: {} bytes exceeds the cache size",
+                height, size
+            );
+            return Ok(());
+        }
+
+        let hash = block.hash();
+        let path = self.entry_path(height, &hash);
+        tokio::task::spawn_blocking(move || -> Result<(), BoxError> {
+            // Write to a temporary file first, so that a crash never leaves a truncated entry behind
+            let temp_path = path.with_extension(TEMP_FILE_EXTENSION);
+            fs::write(&temp_path, &bytes)?;
+            fs::rename(&temp_path, &path)?;
+            Ok(())
+        })
+        .await??;
+
+        let stale_files = {
+            let mut index = self.index.lock().unwrap();
+            let mut stale_files = Vec::new();
+            if let Some(stale) = index.remove(height) {
+                if stale.hash != hash {
+                    stale_files.push(self.entry_path(height, &stale.hash));
+                }
+            }
+            let last_used = index.tick();
+            index.entries.insert(
+                height,
+                CacheEntry {
+                    hash,
+                    size,
+                    last_used,
+                },
+            );
+            index.total_size += size;
+            stale_files.extend(self.evict(&mut index));
+            stale_files
+        };
+        remove_files(stale_files).await;
+        Ok(())
+    }
+
+    /// Removes the least recently used entries from the index until the cache fits within its size limit.
+    /// Returns the files of the removed entries, which the caller must delete.
+    fn evict(&self, index: &mut CacheIndex) -> Vec<PathBuf> {
+        let mut evicted = Vec::new();
+        while index.total_size > self.max_size_bytes {
+            let least_recently_used = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&height, _)| height);
+            let height = match least_recently_used {
+                Some(height) => height,
+                None => break,
+            };
+            let entry = index.remove(height).expect("entry was just found");
+            debug!("Evicting block at height={} from the cache", height);
+            evicted.push(self.entry_path(height, &entry.hash));
+        }
+        evicted
+    }
+
+    fn entry_path(&self, height: u64, hash: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}-{}.{}",
+            height,
+            hex::encode(hash),
+            self.namespaces_key,
+            CACHE_FILE_EXTENSION
+        ))
+    }
+}
+
+/// Checks that a block loaded from disk is the one it was cached as: that it was filtered for `namespaces`, that
+/// its data availability header matches the `data_hash` of its header, and that its rows, shares and pfbs are
+/// those which the header commits to, with every blob paid for by the matching pfb, so that proofs can be built
+/// from it
+fn verify_cached_block(
+    block: &FilteredCelestiaBlock,
+    height: u64,
+    hash: &[u8; 32],
+    namespaces: &BTreeSet<NamespaceId>,
+) -> Result<(), BoxError> {
+    let block_height = block.header.header.height()?;
+    ensure!(
+        block_height == height,
+        "cached block has height {}",
+        block_height
+    );
+    ensure!(&block.hash() == hash, "cached block has the wrong hash");
+    ensure!(
+        block
+            .rollup_data
+            .iter()
+            .map(|data| &data.namespace)
+            .eq(namespaces.iter()),
+        "cached block was filtered for other namespaces"
+    );
+    block
+        .header
+        .validate_dah()
+        .map_err(|e| anyhow::format_err!("{:?}", e))?;
+
+    let dah = &block.header.dah;
+    let pfb_shares = verify_cached_rows(dah, PFB_NAMESPACE, &block.pfb_rows, false)?;
+    let pfbs = parse_pfb_namespace(NamespaceGroup::Compact(pfb_shares))?;
+    ensure!(
+        RelevantPfb::filter(pfbs, namespaces) == block.relevant_pfbs,
+        "cached pfbs don't match the pfb rows"
+    );
+    for data in &block.rollup_data {
+        let shares = verify_cached_rows(dah, data.namespace, &data.rows, true)?;
+        ensure!(
+            data.data.shares() == &shares,
+            "cached shares of namespace 0x{} don't match its rows",
+            hex::encode(data.namespace)
+        );
+    }
+    block.check_blob_pfbs()?;
+    Ok(check_correctness_proof(block)?)
+}
+
+/// Checks that `rows` are the rows of the square whose roots contain `namespace` (or, if `with_absence_row` is set
+/// and the namespace is absent, the row which proves its absence), and that each of them hashes to its root.
+/// Returns the shares of the namespace within the rows.
+fn verify_cached_rows(
+    dah: &DataAvailabilityHeader,
+    namespace: NamespaceId,
+    rows: &[Row],
+    with_absence_row: bool,
+) -> Result<Vec<Share>, BoxError> {
+    let width = dah.row_roots.len();
+    let expected_roots: Vec<&NamespacedHash> = match dah.absence_row(namespace) {
+        Some(row_idx) if with_absence_row => vec![&dah.row_roots[row_idx]],
+        _ => dah
+            .row_roots
+            .iter()
+            .filter(|root| root.contains(namespace))
+            .collect(),
+    };
+    ensure!(
+        rows.iter().map(|row| &row.root).eq(expected_roots),
+        "cached rows of namespace 0x{} don't match the row roots",
+        hex::encode(namespace)
+    );
+    let mut shares = Vec::new();
+    for row in rows {
+        ensure!(
+            row.shares.len() == width,
+            "cached row has {} shares instead of {}",
+            row.shares.len(),
+            width
+        );
+        let root = Row::tree_of(&row.shares).map(|mut tree| tree.root());
+        ensure!(
+            root.as_ref() == Some(&row.root),
+            "cached row doesn't match its root"
+        );
+        let ods_shares = &row.shares[..width / 2];
+        shares.extend(
+            ods_shares
+                .iter()
+                .filter(|share| share.namespace() == namespace)
+                .cloned(),
+        );
+    }
+    Ok(shares)
+}
+
+/// Identifies a set of namespaces by a short hash of their concatenation
+fn namespaces_key(namespaces: &BTreeSet<NamespaceId>) -> String {
+    let bytes: Vec<u8> = namespaces
+        .iter()
+        .flat_map(|namespace| namespace.0)
+        .collect();
+    hex::encode(&Sha256::digest(&bytes)[..8])
+}
+
+fn parse_file_name(path: &Path) -> Option<(u64, [u8; 32], &str)> {
+    if path.extension()? != CACHE_FILE_EXTENSION {
+        return None;
+    }
+    let mut parts = path.file_stem()?.to_str()?.split('-');
+    let height = parts.next()?.parse().ok()?;
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(parts.next()?, &mut hash).ok()?;
+    let namespaces_key = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((height, hash, namespaces_key))
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove cached block {}: {}", path.display(), e);
+    }
+}
+
+async fn remove_files(paths: Vec<PathBuf>) {
+    if paths.is_empty() {
+        return;
+    }
+    let removal =
+        tokio::task::spawn_blocking(move || paths.iter().for_each(|path| remove_file(path)));
+    if let Err(e) = removal.await {
+        warn!("Failed to remove cached blocks: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, fs, path::PathBuf};
+
+    use borsh::BorshSerialize;
+    use nmt_rs::NamespaceId;
+
+    use super::{BlockCache, BlockCacheConfig};
+    use crate::{
+        shares::NamespaceGroup,
+        test_utils::{single_blob_block, TEST_NAMESPACE},
+        types::FilteredCelestiaBlock,
+    };
+
+    fn test_block() -> (FilteredCelestiaBlock, u64) {
+        let (block, _) = single_blob_block(4, vec![7u8; 1000]);
+        let height = block.header.header.height().unwrap();
+        (block, height)
+    }
+
+    fn namespaces() -> BTreeSet<NamespaceId> {
+        BTreeSet::from([TEST_NAMESPACE])
+    }
+
+    fn config(name: &str, max_size_bytes: u64) -> BlockCacheConfig {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "jupiter-block-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        BlockCacheConfig {
+            path,
+            max_size_bytes,
+        }
+    }
+
+    fn cached_files(config: &BlockCacheConfig) -> usize {
+        fs::read_dir(&config.path).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn test_cached_block_roundtrip() {
+        let (block, height) = test_block();
+        let config = config("roundtrip", u64::MAX);
+        let cache = BlockCache::open(&config, &namespaces()).unwrap();
+        assert!(cache.get(height).await.is_none());
+        cache.insert(height, &block).await.unwrap();
+        assert_eq!(cache.get(height).await, Some(block.clone()));
+
+        // The block survives reopening the cache
+        let reopened = BlockCache::open(&config, &namespaces()).unwrap();
+        assert_eq!(reopened.get(height).await, Some(block));
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blocks_cached_for_other_namespaces_are_ignored() {
+        let (block, height) = test_block();
+        let config = config("namespaces", u64::MAX);
+        let cache = BlockCache::open(&config, &namespaces()).unwrap();
+        cache.insert(height, &block).await.unwrap();
+
+        let other_namespaces = BTreeSet::from([TEST_NAMESPACE, NamespaceId([0xee; 8])]);
+        let other = BlockCache::open(&config, &other_namespaces).unwrap();
+        assert!(other.get(height).await.is_none());
+        // The block is still there for the namespaces it was filtered for
+        assert_eq!(cached_files(&config), 1);
+        assert_eq!(cache.get(height).await, Some(block.clone()));
+
+        // A block filtered for other namespaces is rejected even if it was stored under this cache's name
+        other.insert(height, &block).await.unwrap();
+        assert!(other.get(height).await.is_none());
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_cached_block_is_discarded() {
+        let (mut block, height) = test_block();
+        block.header.dah.row_roots.swap(0, 1);
+        let config = config("invalid", u64::MAX);
+        let cache = BlockCache::open(&config, &namespaces()).unwrap();
+        cache.insert(height, &block).await.unwrap();
+        assert!(cache.get(height).await.is_none());
+        assert_eq!(cached_files(&config), 0);
+
+        // A block stored under the wrong height is also rejected
+        let (block, height) = test_block();
+        cache.insert(height + 1, &block).await.unwrap();
+        assert!(cache.get(height + 1).await.is_none());
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_rows_and_shares_are_discarded() {
+        let config = config("tampered", u64::MAX);
+        let cache = BlockCache::open(&config, &namespaces()).unwrap();
+
+        // Rows which don't hash to their roots would make building their trees panic
+        let (mut block, height) = test_block();
+        block.rollup_data[0].rows[0].shares.reverse();
+        cache.insert(height, &block).await.unwrap();
+        assert!(cache.get(height).await.is_none());
+
+        let (mut block, height) = test_block();
+        block.pfb_rows[0].shares.swap(0, 1);
+        cache.insert(height, &block).await.unwrap();
+        assert!(cache.get(height).await.is_none());
+
+        // The shares of a namespace must be those in its rows
+        let (mut block, height) = test_block();
+        block.rollup_data[0].data = NamespaceGroup::Sparse(vec![]);
+        cache.insert(height, &block).await.unwrap();
+        assert!(cache.get(height).await.is_none());
+
+        let (mut block, height) = test_block();
+        block.relevant_pfbs.clear();
+        cache.insert(height, &block).await.unwrap();
+        assert!(cache.get(height).await.is_none());
+        assert_eq!(cached_files(&config), 0);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_block_is_evicted() {
+        let (block, height) = test_block();
+        let size = block.try_to_vec().unwrap().len() as u64;
+        let config = config("eviction", size * 2);
+        let cache = BlockCache::open(&config, &namespaces()).unwrap();
+        cache.insert(height, &block).await.unwrap();
+        cache.insert(height + 1, &block).await.unwrap();
+        assert_eq!(cached_files(&config), 2);
+
+        // Touch the first entry, so that the second one is evicted next
+        assert!(cache.get(height).await.is_some());
+        cache.insert(height + 2, &block).await.unwrap();
+        assert_eq!(cached_files(&config), 2);
+        assert!(cache.get(height).await.is_some());
+        assert!(cache
+            .index
+            .lock()
+            .unwrap()
+            .entries
+            .get(&(height + 1))
+            .is_none());
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+}
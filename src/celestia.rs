@@ -2,14 +2,18 @@ use std::fmt::{Display, Formatter};
 use std::{cell::RefCell, ops::Range};
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use nmt_rs::NamespacedHash;
+use nmt_rs::{NamespaceId, NamespacedHash};
 use prost::{bytes::Buf, Message};
 use serde::{Deserialize, Serialize};
 use sovereign_sdk::core::traits::{
     AddressTrait as Address, BlockHeaderTrait as BlockHeader, CanonicalHash,
 };
 pub use tendermint::block::Header as TendermintHeader;
-use tendermint::{crypto::default::Sha256, merkle::simple_hash_from_byte_vectors, Hash};
+use tendermint::{
+    crypto::{default::Sha256, Sha256 as _},
+    merkle::simple_hash_from_byte_vectors,
+    Hash,
+};
 use tendermint_proto::Protobuf;
 use tracing::debug;
 
@@ -20,9 +24,10 @@ const NAMESPACED_HASH_LEN: usize = 48;
 use crate::{
     pfb::{BlobTx, MsgPayForBlobs, Tx},
     shares::{read_varint, Blob, BlobRefIterator, NamespaceGroup},
+    types::ValidationError,
     utils::BoxError,
-    verifier::PFB_NAMESPACE,
     verifier::{address::CelestiaAddress, TmHash},
+    verifier::{MAX_ODS_WIDTH, PARITY_SHARES_NAMESPACE, PFB_NAMESPACE},
 };
 
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
@@ -151,6 +156,12 @@ pub fn protobuf_encode(hash: &Option<ProtobufHash>) -> Vec<u8> {
 }
 
 impl CompactHeader {
+    /// Decodes the height of the block
+    pub fn height(&self) -> Result<u64, BoxError> {
+        let height = <tendermint::block::Height as Protobuf<i64>>::decode_vec(&self.height)?;
+        Ok(height.value())
+    }
+
     /// Hash this header
     // TODO: this function can be made even more efficient. Rather than computing the block hash,
     // we could provide the hash as a non-deterministic input and simply verify the correctness of the
@@ -189,15 +200,98 @@ pub struct DataAvailabilityHeader {
     pub column_roots: Vec<NamespacedHash>,
 }
 
-// Danger! This method panics if the provided bas64 is longer than a namespaced hash
-fn decode_to_ns_hash(b64: &str) -> Result<NamespacedHash, base64::DecodeError> {
-    let mut out = [0u8; NAMESPACED_HASH_LEN];
-    base64::decode_config_slice(b64, base64::STANDARD, &mut out)?;
+impl DataAvailabilityHeader {
+    /// If no row root contains `namespace`, returns the index of the row in which its shares would have
+    /// been stored. That row is used to prove the absence of the namespace.
+    pub fn absence_row(&self, namespace: NamespaceId) -> Option<usize> {
+        if self.row_roots.iter().any(|root| root.contains(namespace)) {
+            return None;
+        }
+        self.row_roots
+            .iter()
+            .position(|root| root.min_namespace() > namespace)
+    }
+
+    /// Checks that the roots have the shape of an extended data square: a square of a permitted size, whose
+    /// original quadrant is sorted by namespace and whose extended rows and columns hold only parity shares.
+    /// This doesn't check the roots against the header's `data_hash`.
+    pub fn validate_structure(&self) -> Result<(), ValidationError> {
+        let width = self.row_roots.len();
+        if self.column_roots.len() != width {
+            return Err(ValidationError::MismatchedRootCount);
+        }
+        let ods_width = width / 2;
+        if width % 2 != 0 || !ods_width.is_power_of_two() || ods_width > MAX_ODS_WIDTH {
+            return Err(ValidationError::InvalidSquareSize);
+        }
+
+        let mut all_roots = self.row_roots.iter().chain(self.column_roots.iter());
+        if all_roots.any(|root| root.min_namespace() > root.max_namespace()) {
+            return Err(ValidationError::InvalidNamespaceRange);
+        }
+        let is_parity = |root: &NamespacedHash| {
+            root.min_namespace() == PARITY_SHARES_NAMESPACE
+                && root.max_namespace() == PARITY_SHARES_NAMESPACE
+        };
+        for roots in [&self.row_roots, &self.column_roots] {
+            let (original, extended) = roots.split_at(ods_width);
+            // Parity shares are ignored when computing the maximum namespace, so only the extended half of
+            // the square can have a parity namespace
+            let original_has_parity = original.iter().any(|root| {
+                root.min_namespace() == PARITY_SHARES_NAMESPACE
+                    || root.max_namespace() == PARITY_SHARES_NAMESPACE
+            });
+            if original_has_parity || !extended.iter().all(is_parity) {
+                return Err(ValidationError::MisplacedParityRoot);
+            }
+        }
+
+        // Shares are laid out in namespace order, so each row ends at or before the start of the next one
+        for [row, next] in self.row_roots[..ods_width].array_windows() {
+            if row.max_namespace() > next.min_namespace() {
+                return Err(ValidationError::UnsortedRowRoots);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A root of a [`MarshalledDataAvailabilityHeader`] which is not a base64 encoded [`NamespacedHash`]
+#[derive(Debug, PartialEq, Clone)]
+pub enum DahDecodeError {
+    InvalidBase64(base64::DecodeError),
+    /// The root decoded to the wrong number of bytes
+    WrongLength(usize),
+}
+
+impl Display for DahDecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DahDecodeError::InvalidBase64(e) => write!(f, "DahDecodeError::InvalidBase64({})", e),
+            DahDecodeError::WrongLength(len) => write!(f, "DahDecodeError::WrongLength({})", len),
+        }
+    }
+}
+
+impl std::error::Error for DahDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DahDecodeError::InvalidBase64(e) => Some(e),
+            DahDecodeError::WrongLength(_) => None,
+        }
+    }
+}
+
+fn decode_to_ns_hash(b64: &str) -> Result<NamespacedHash, DahDecodeError> {
+    let decoded = base64::decode(b64).map_err(DahDecodeError::InvalidBase64)?;
+    let out: [u8; NAMESPACED_HASH_LEN] = decoded
+        .try_into()
+        .map_err(|decoded: Vec<u8>| DahDecodeError::WrongLength(decoded.len()))?;
     Ok(NamespacedHash(out))
 }
 
 impl TryFrom<MarshalledDataAvailabilityHeader> for DataAvailabilityHeader {
-    type Error = base64::DecodeError;
+    type Error = DahDecodeError;
 
     fn try_from(value: MarshalledDataAvailabilityHeader) -> Result<Self, Self::Error> {
         let mut row_roots = Vec::with_capacity(value.row_roots.len());
@@ -215,14 +309,48 @@ impl TryFrom<MarshalledDataAvailabilityHeader> for DataAvailabilityHeader {
     }
 }
 
-/// The response from the celestia `/header` endpoint. Must be converted to a
-/// [`CelestiaHeader`] before use.
+/// A header as returned and accepted by the celestia-node `header` and `share` modules: the tendermint
+/// header, the commit and validator set which sign it, and the data availability header. Must be converted
+/// to a [`CelestiaHeader`] before use.
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
-pub struct CelestiaHeaderResponse {
+pub struct ExtendedHeader {
     pub header: tendermint::block::Header,
+    pub commit: tendermint::block::Commit,
+    pub validator_set: ValidatorSet,
     pub dah: MarshalledDataAvailabilityHeader,
 }
 
+impl ExtendedHeader {
+    pub fn height(&self) -> u64 {
+        self.header.height.value()
+    }
+}
+
+impl TryFrom<ExtendedHeader> for CelestiaHeader {
+    type Error = DahDecodeError;
+
+    fn try_from(value: ExtendedHeader) -> Result<Self, Self::Error> {
+        Ok(CelestiaHeader::new(
+            value.dah.try_into()?,
+            value.header.into(),
+        ))
+    }
+}
+
+/// The validator set of an [`ExtendedHeader`]. Unlike [`tendermint::validator::Set`], celestia-node doesn't
+/// include the total voting power.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct ValidatorSet {
+    pub validators: Vec<tendermint::validator::Info>,
+    pub proposer: Option<tendermint::validator::Info>,
+}
+
+impl From<ValidatorSet> for tendermint::validator::Set {
+    fn from(value: ValidatorSet) -> Self {
+        tendermint::validator::Set::new(value.validators, value.proposer)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NamespacedSharesResponse {
     pub shares: Option<Vec<String>>,
@@ -264,6 +392,43 @@ impl CanonicalHash for CelestiaHeader {
 pub struct BlobWithSender {
     pub blob: Blob,
     pub sender: CelestiaAddress,
+    pub metadata: BlobMetadata,
+}
+
+/// Describes where a blob was included in the block, and which PFB paid for it
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+pub struct BlobMetadata {
+    /// The namespace in which the blob was included
+    pub namespace: NamespaceId,
+    /// The index of the blob's first share in the original data square
+    pub start_share_idx: usize,
+    /// The row of the blob's first share
+    pub row: usize,
+    /// The column of the blob's first share
+    pub column: usize,
+    /// The share commitment of the blob
+    pub commitment: [u8; 32],
+    /// The hash of the transaction containing the PFB which paid for the blob
+    pub pfb_tx_hash: [u8; 32],
+}
+
+impl BlobMetadata {
+    pub fn new(
+        namespace: NamespaceId,
+        start_share_idx: usize,
+        ods_width: usize,
+        commitment: [u8; 32],
+        pfb_tx_hash: [u8; 32],
+    ) -> Self {
+        Self {
+            namespace,
+            start_share_idx,
+            row: start_share_idx / ods_width,
+            column: start_share_idx % ods_width,
+            commitment,
+            pfb_tx_hash,
+        }
+    }
 }
 
 impl BlockHeader for CelestiaHeader {
@@ -346,16 +511,29 @@ impl Display for H160 {
 
 impl Address for H160 {}
 
-pub fn parse_pfb_namespace(
-    group: NamespaceGroup,
-) -> Result<Vec<(MsgPayForBlobs, TxPosition)>, BoxError> {
+/// A PFB parsed from the pfb namespace
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParsedPfb {
+    pub pfb: MsgPayForBlobs,
+    pub position: TxPosition,
+    /// The hash of the cosmos sdk transaction containing the PFB
+    pub tx_hash: [u8; 32],
+}
+
+pub fn parse_pfb_namespace(group: NamespaceGroup) -> Result<Vec<ParsedPfb>, BoxError> {
     if group.shares().len() == 0 {
         return Ok(vec![]);
     }
-    assert!(group.shares()[0].namespace() == PFB_NAMESPACE);
+    let namespace = group.shares()[0].namespace();
+    if namespace != PFB_NAMESPACE {
+        return Err(anyhow::format_err!(
+            "Expected shares in the pfb namespace, found 0x{}",
+            hex::encode(namespace)
+        ));
+    }
     let mut pfbs = Vec::new();
     for blob in group.blobs() {
-        let mut data = blob.data();
+        let mut data = blob.try_data()?;
         while data.has_remaining() {
             pfbs.push(next_pfb(&mut data)?)
         }
@@ -367,16 +545,23 @@ pub fn parse_pfb_namespace(
     Debug, PartialEq, Clone, serde::Serialize, Deserialize, BorshSerialize, BorshDeserialize,
 )]
 pub struct TxPosition {
-    /// The half-open range of shares across which this transaction is serialized.
-    /// For example a transaction which was split across shares 5,6, and 7 would have range 5..8
+    /// The half-open range of shares across which this transaction is serialized, counted from the first
+    /// share of the pfb namespace. For example a transaction which was split across shares 5,6, and 7 would
+    /// have range 5..8
     pub share_range: Range<usize>,
     /// The offset into the first share at which the transaction starts
     pub start_offset: usize,
 }
 
-pub(crate) fn pfb_from_iter(data: impl Buf, pfb_len: usize) -> Result<MsgPayForBlobs, BoxError> {
+/// Decodes a PFB from its wrapping transaction. Returns the PFB along with the hash of the cosmos sdk
+/// transaction which contains it
+pub(crate) fn pfb_from_iter(
+    data: impl Buf,
+    pfb_len: usize,
+) -> Result<(MsgPayForBlobs, [u8; 32]), BoxError> {
     debug!("Decoding blob tx");
     let mut blob_tx = BlobTx::decode(data.take(pfb_len))?;
+    let tx_hash = Sha256::digest(&blob_tx.tx);
     debug!("Decoding cosmos sdk tx");
     let cosmos_tx = Tx::decode(&mut blob_tx.tx)?;
     let messages = cosmos_tx
@@ -387,39 +572,55 @@ pub(crate) fn pfb_from_iter(data: impl Buf, pfb_len: usize) -> Result<MsgPayForB
         return Err(anyhow::format_err!("Expected 1 message in cosmos tx"));
     }
     debug!("Decoding PFB from blob tx value");
-    Ok(MsgPayForBlobs::decode(&mut &messages[0].value[..])?)
+    Ok((
+        MsgPayForBlobs::decode(&mut &messages[0].value[..])?,
+        tx_hash,
+    ))
 }
 
-fn next_pfb(mut data: &mut BlobRefIterator) -> Result<(MsgPayForBlobs, TxPosition), BoxError> {
+fn next_pfb(mut data: &mut BlobRefIterator) -> Result<ParsedPfb, BoxError> {
     let (start_idx, start_offset) = data.current_position();
-    let (len, len_of_len) = read_varint(&mut data).expect("Varint must be valid");
+    let (len, len_of_len) = read_varint(&mut data)?;
     debug!(
         "Decoding wrapped PFB of length {}. Stripped {} bytes of prefix metadata",
         len, len_of_len
     );
 
-    let current_share_idx = data.current_position().0;
-    let pfb = pfb_from_iter(&mut data, len as usize)?;
+    let (pfb, tx_hash) = pfb_from_iter(&mut data, len as usize)?;
+    // If the pfb ended exactly at a share boundary, the iterator has already moved on to the next share
+    let (end_idx, end_offset) = data.current_position();
+    let share_range_end = if end_offset == 0 {
+        end_idx
+    } else {
+        end_idx + 1
+    };
 
-    Ok((
+    Ok(ParsedPfb {
         pfb,
-        TxPosition {
-            share_range: start_idx..current_share_idx + 1,
+        position: TxPosition {
+            share_range: start_idx..share_range_end,
             start_offset,
         },
-    ))
+        tx_hash,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{CelestiaHeaderResponse, CompactHeader};
+    use crate::{
+        parse_pfb_namespace,
+        shares::{NamespaceGroup, Share},
+        test_utils::{compact_shares, sparse_shares, TEST_NAMESPACE},
+        verifier::PFB_NAMESPACE,
+        CelestiaHeader, CompactHeader, DahDecodeError, DataAvailabilityHeader, ExtendedHeader,
+        MarshalledDataAvailabilityHeader,
+    };
 
     const HEADER_RESPONSE_JSON: &[u8] = include_bytes!("./header_response.json");
 
     #[test]
     fn test_compact_header_serde() {
-        let original_header: CelestiaHeaderResponse =
-            serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
+        let original_header: ExtendedHeader = serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
 
         let header: CompactHeader = original_header.header.into();
 
@@ -430,12 +631,117 @@ mod tests {
 
     #[test]
     fn test_compact_header_hash() {
-        let original_header: CelestiaHeaderResponse =
-            serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
+        let original_header: ExtendedHeader = serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
 
         let tm_header = original_header.header.clone();
         let compact_header: CompactHeader = original_header.header.into();
 
         assert_eq!(tm_header.hash(), compact_header.hash());
     }
+
+    /// Responses to `header.GetByHeight`, each captured verbatim from a node. Fixtures must never be edited by
+    /// hand, since the checks below rely on each header hashing to the block which its commit signs.
+    const HEADER_FIXTURES: &[(&str, &[u8])] = &[("mocha height 428545", HEADER_RESPONSE_JSON)];
+
+    #[test]
+    fn test_captured_header_fixtures() {
+        for (name, fixture) in HEADER_FIXTURES {
+            let header: ExtendedHeader = serde_json::from_slice(fixture)
+                .unwrap_or_else(|e| panic!("{} must deserialize: {}", name, e));
+            assert_eq!(header.commit.height, header.header.height, "{}", name);
+            // The decoded header must hash to the block its commit signs
+            assert_eq!(
+                header.header.hash(),
+                header.commit.block_id.hash,
+                "{}",
+                name
+            );
+            let validators: tendermint::validator::Set = header.validator_set.clone().into();
+            assert_eq!(validators.hash(), header.header.validators_hash, "{}", name);
+
+            // The header is sent back to the node as a request parameter, so it must roundtrip
+            let json = serde_json::to_value(&header).unwrap();
+            assert_eq!(
+                serde_json::from_value::<ExtendedHeader>(json).unwrap(),
+                header,
+                "{}",
+                name
+            );
+
+            let celestia_header: CelestiaHeader = header.try_into().unwrap();
+            celestia_header
+                .validate_dah()
+                .unwrap_or_else(|e| panic!("{} has an invalid dah: {:?}", name, e));
+        }
+    }
+
+    #[test]
+    fn test_extended_header_fixture() {
+        let header: ExtendedHeader = serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
+        assert_eq!(header.height(), 428545);
+        assert_eq!(header.validator_set.validators.len(), 100);
+
+        // Every field of the header is covered by its hash, including the app version
+        let mut json: serde_json::Value = serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
+        json["header"]["version"]["app"] = "1".into();
+        let edited: ExtendedHeader = serde_json::from_value(json).unwrap();
+        assert_eq!(edited.header.version.app, 1);
+        assert_eq!(header.commit.block_id.hash, edited.commit.block_id.hash);
+        assert_ne!(edited.header.hash(), edited.commit.block_id.hash);
+    }
+
+    #[test]
+    fn test_malformed_dah_roots() {
+        let header: ExtendedHeader = serde_json::from_slice(HEADER_RESPONSE_JSON).unwrap();
+        let decode = |root: String| {
+            let mut dah = header.dah.clone();
+            dah.row_roots[0] = root;
+            DataAvailabilityHeader::try_from(dah)
+        };
+        let valid = base64::decode(&header.dah.row_roots[0]).unwrap();
+        assert!(decode(base64::encode(&valid)).is_ok());
+
+        // Longer and shorter roots are rejected, rather than truncated or zero padded
+        let mut long = valid.clone();
+        long.push(0);
+        assert_eq!(
+            decode(base64::encode(&long)),
+            Err(DahDecodeError::WrongLength(49))
+        );
+        assert_eq!(
+            decode(base64::encode(&valid[..47])),
+            Err(DahDecodeError::WrongLength(47))
+        );
+        assert_eq!(decode(String::new()), Err(DahDecodeError::WrongLength(0)));
+        assert!(matches!(
+            decode("not base64!".to_string()),
+            Err(DahDecodeError::InvalidBase64(_))
+        ));
+
+        let empty = MarshalledDataAvailabilityHeader {
+            row_roots: vec![],
+            column_roots: vec![],
+        };
+        assert!(DataAvailabilityHeader::try_from(empty).is_ok());
+    }
+
+    #[test]
+    fn test_malformed_pfb_namespace() {
+        let group = |shares: Vec<Vec<u8>>| {
+            NamespaceGroup::Compact(shares.into_iter().map(|s| Share::new(s.into())).collect())
+        };
+        // Shares from the wrong namespace
+        let shares = sparse_shares(TEST_NAMESPACE, b"not a pfb");
+        assert!(parse_pfb_namespace(group(shares)).is_err());
+        // A sequence which ends in the middle of a length prefix
+        let shares = compact_shares(PFB_NAMESPACE, &[0x80], &[0]);
+        assert!(parse_pfb_namespace(group(shares)).is_err());
+        // A length prefix which claims more data than the sequence holds
+        let shares = compact_shares(PFB_NAMESPACE, &[0x7f, 1, 2, 3], &[0]);
+        assert!(parse_pfb_namespace(group(shares)).is_err());
+        // A group which starts with a continuation share
+        let mut shares = compact_shares(PFB_NAMESPACE, &[0u8; 1000], &[0]);
+        shares.remove(0);
+        assert!(parse_pfb_namespace(group(shares)).is_err());
+    }
 }
@@ -0,0 +1,199 @@
+//! Decoding of the [CIDs](https://github.com/multiformats/cid) which celestia-node uses to identify the nodes
+//! of namespaced merkle trees. A CID is a multibase string wrapping the binary encoding
+//! `<version><codec><multihash code><digest length><digest>`, where each of the first four fields is a varint.
+use std::fmt::Display;
+
+use nmt_rs::{NamespacedHash, NAMESPACED_HASH_LEN};
+
+/// The multibase prefix of lowercase, unpadded base32
+const BASE32_PREFIX: char = 'b';
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const CID_VERSION: u64 = 1;
+/// The multicodec of an NMT node
+pub const NMT_CODEC: u64 = 0x7700;
+/// The multihash code of the sha256 namespaced hash used by celestia's NMTs
+pub const NMT_MULTIHASH_CODE: u64 = 0x7701;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CidError {
+    /// The CID is not base32 encoded
+    UnsupportedMultibase(Option<char>),
+    InvalidBase32,
+    InvalidVarint,
+    UnsupportedVersion(u64),
+    UnsupportedCodec(u64),
+    UnsupportedMultihash(u64),
+    WrongDigestLength(u64),
+    /// The CID continues after the end of its digest
+    TrailingBytes,
+}
+
+impl Display for CidError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CidError::UnsupportedMultibase(prefix) => {
+                write!(f, "CidError::UnsupportedMultibase({:?})", prefix)
+            }
+            CidError::InvalidBase32 => f.write_str("CidError::InvalidBase32"),
+            CidError::InvalidVarint => f.write_str("CidError::InvalidVarint"),
+            CidError::UnsupportedVersion(version) => {
+                write!(f, "CidError::UnsupportedVersion({})", version)
+            }
+            CidError::UnsupportedCodec(codec) => {
+                write!(f, "CidError::UnsupportedCodec(0x{:x})", codec)
+            }
+            CidError::UnsupportedMultihash(code) => {
+                write!(f, "CidError::UnsupportedMultihash(0x{:x})", code)
+            }
+            CidError::WrongDigestLength(len) => write!(f, "CidError::WrongDigestLength({})", len),
+            CidError::TrailingBytes => f.write_str("CidError::TrailingBytes"),
+        }
+    }
+}
+
+impl std::error::Error for CidError {}
+
+/// Decodes the CID of an NMT node into the node's namespaced hash
+pub fn ns_hash_from_cid(cid: &str) -> Result<NamespacedHash, CidError> {
+    let mut chars = cid.chars();
+    match chars.next() {
+        Some(BASE32_PREFIX) => {}
+        prefix => return Err(CidError::UnsupportedMultibase(prefix)),
+    }
+    let bytes = decode_base32(chars.as_str())?;
+    let mut buf = &bytes[..];
+    let mut next_varint =
+        || prost::encoding::decode_varint(&mut buf).map_err(|_| CidError::InvalidVarint);
+
+    let version = next_varint()?;
+    if version != CID_VERSION {
+        return Err(CidError::UnsupportedVersion(version));
+    }
+    let codec = next_varint()?;
+    if codec != NMT_CODEC {
+        return Err(CidError::UnsupportedCodec(codec));
+    }
+    let multihash = next_varint()?;
+    if multihash != NMT_MULTIHASH_CODE {
+        return Err(CidError::UnsupportedMultihash(multihash));
+    }
+    let digest_len = next_varint()?;
+    if digest_len != NAMESPACED_HASH_LEN as u64 {
+        return Err(CidError::WrongDigestLength(digest_len));
+    }
+    if buf.len() < NAMESPACED_HASH_LEN {
+        return Err(CidError::WrongDigestLength(buf.len() as u64));
+    }
+    if buf.len() > NAMESPACED_HASH_LEN {
+        return Err(CidError::TrailingBytes);
+    }
+    let mut digest = [0u8; NAMESPACED_HASH_LEN];
+    digest.copy_from_slice(buf);
+    Ok(NamespacedHash(digest))
+}
+
+/// Encodes a namespaced hash as the CID of an NMT node. The inverse of [`ns_hash_from_cid`].
+pub fn ns_hash_to_cid(hash: &NamespacedHash) -> String {
+    let mut bytes = Vec::with_capacity(NAMESPACED_HASH_LEN + 8);
+    for prefix in [
+        CID_VERSION,
+        NMT_CODEC,
+        NMT_MULTIHASH_CODE,
+        NAMESPACED_HASH_LEN as u64,
+    ] {
+        prost::encoding::encode_varint(prefix, &mut bytes);
+    }
+    bytes.extend_from_slice(&hash.0);
+
+    let mut cid = String::with_capacity(1 + (bytes.len() * 8 + 4) / 5);
+    cid.push(BASE32_PREFIX);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            cid.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        cid.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    cid
+}
+
+/// Decodes unpadded base32 using the lowercase RFC 4648 alphabet
+fn decode_base32(input: &str) -> Result<Vec<u8>, CidError> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&symbol| symbol == c)
+            .ok_or(CidError::InvalidBase32)? as u16;
+        buffer = ((buffer << 5) | value) & 0x1fff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    // Any leftover bits are padding, and must be zero
+    if bits >= 5 || buffer & ((1 << bits) - 1) != 0 {
+        return Err(CidError::InvalidBase32);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use nmt_rs::NamespacedHash;
+
+    use super::{ns_hash_from_cid, ns_hash_to_cid, CidError};
+
+    const NMT_NODE_CID: &str =
+        "bagao4amb5yatb7777777777773777777777777tjxe2jqsatxobgu3jqwkwsefsxscursxyaqzvvrxzv73aphwunua";
+
+    #[test]
+    fn test_decode_nmt_node_cid() {
+        let hash = ns_hash_from_cid(NMT_NODE_CID).unwrap();
+        assert_eq!(
+            hex::encode(hash.0),
+            "fffffffffffffffefffffffffffffffe69b934984813bb826a6d30b2ad22165790a9195f00866b58df35fec0f3da8da0"
+        );
+        assert_eq!(ns_hash_to_cid(&hash), NMT_NODE_CID);
+
+        let hash = NamespacedHash([7u8; 48]);
+        assert_eq!(ns_hash_from_cid(&ns_hash_to_cid(&hash)).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_malformed_cids() {
+        assert_eq!(
+            ns_hash_from_cid(""),
+            Err(CidError::UnsupportedMultibase(None))
+        );
+        assert_eq!(
+            ns_hash_from_cid("zQm"),
+            Err(CidError::UnsupportedMultibase(Some('z')))
+        );
+        assert_eq!(ns_hash_from_cid("bagao1"), Err(CidError::InvalidBase32));
+        // Truncated inside the varint prefix, and inside the digest. The 88 characters after the prefix encode
+        // exactly 55 bytes, so the truncated digest is still valid base32 and holds 47 of the 48 bytes.
+        assert_eq!(ns_hash_from_cid("bagaa"), Err(CidError::InvalidVarint));
+        assert_eq!(
+            ns_hash_from_cid(&NMT_NODE_CID[..89]),
+            Err(CidError::WrongDigestLength(47))
+        );
+        // A CIDv0 style multihash
+        let mut cid = NMT_NODE_CID.to_string();
+        cid.replace_range(1..3, "aa");
+        assert!(ns_hash_from_cid(&cid).is_err());
+        // Extra data after the digest
+        let cid = format!("{}aaa", ns_hash_to_cid(&NamespacedHash([1u8; 48])));
+        assert_eq!(ns_hash_from_cid(&cid), Err(CidError::TrailingBytes));
+    }
+}
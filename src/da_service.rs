@@ -1,111 +1,954 @@
-use std::{collections::HashMap, future::Future, pin::Pin};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesOrdered, Stream, StreamExt};
 
+use anyhow::{ensure, Context};
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
 use jsonrpsee::{
-    core::client::ClientT,
+    core::{client::CertificateStore, Error as JsonRpcError},
     http_client::{HeaderMap, HttpClient},
+    rpc_params,
 };
-use nmt_rs::NamespaceId;
+use nmt_rs::{NamespaceId, NamespaceProof, NamespacedHash, NamespacedSha2Hasher};
 use sovereign_sdk::services::da::DaService;
-use tracing::{debug, info, span, Level};
+use tracing::{debug, info, span, warn, Level};
 
 // 0x736f762d74657374 = b"sov-test"
 // For testing, use this NamespaceId (b"sov-test"):
 // pub const ROLLUP_NAMESPACE: NamespaceId = NamespaceId([115, 111, 118, 45, 116, 101, 115, 116]);
 
 use crate::{
+    block_cache::{BlockCache, BlockCacheConfig},
     parse_pfb_namespace,
+    rpc::{FailoverConfig, RetryConfig, RpcClient, RpcError, RpcTransport, TransportOptions},
     share_commit::recreate_commitment,
     shares::{NamespaceGroup, Share},
-    types::{ExtendedDataSquare, FilteredCelestiaBlock, Row, RpcNamespacedSharesResponse},
+    telemetry,
+    types::{
+        ExtendedDataSquare, FilteredCelestiaBlock, RelevantPfb, RollupNamespaceData, Row,
+        RpcNamespacedSharesResponse,
+    },
     utils::BoxError,
     verifier::{
         address::CelestiaAddress,
-        proofs::{CompletenessProof, CorrectnessProof},
+        proofs::{CompletenessProof, CorrectnessProof, ProofGenerationError},
         CelestiaSpec, RollupParams, PFB_NAMESPACE,
     },
-    BlobWithSender, CelestiaHeader, CelestiaHeaderResponse, DataAvailabilityHeader,
+    BlobMetadata, BlobWithSender, CelestiaHeader, DahDecodeError, DataAvailabilityHeader,
+    ExtendedHeader, MarshalledDataAvailabilityHeader,
 };
 
 #[derive(Debug, Clone)]
 pub struct CelestiaService {
-    client: HttpClient,
-    rollup_namespace: NamespaceId,
+    client: RpcClient,
+    rollup_namespaces: BTreeSet<NamespaceId>,
+    /// How often to poll the node while waiting for a block to be produced
+    head_poll_interval: Duration,
+    /// The highest block height which the node is known to have produced
+    known_head: Arc<AtomicU64>,
+    /// An optional persistent cache of fetched blocks
+    cache: Option<BlockCache>,
+    /// Whether to compare each header with a second endpoint
+    cross_check_headers: bool,
+    /// How the block streams retry a height whose fetch failed
+    prefetch_retry: RetryConfig,
+}
+
+/// Returned when a block is requested at a height which the chain has not reached yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockNotYetProduced {
+    /// The requested height
+    pub height: u64,
+    /// The height of the node's latest block
+    pub head: u64,
+}
+
+impl Display for BlockNotYetProduced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "BlockNotYetProduced {{ height: {}, head: {} }}",
+            self.height, self.head
+        )
+    }
+}
+
+impl std::error::Error for BlockNotYetProduced {}
+
+/// An error returned by the [`CelestiaService`]
+#[derive(Debug)]
+pub enum DaServiceError {
+    /// The requested block has not been produced yet
+    NotYetProduced(BlockNotYetProduced),
+    /// An RPC call failed
+    Rpc {
+        method: &'static str,
+        height: Option<u64>,
+        source: RpcError,
+    },
+    /// A response could not be decoded
+    InvalidJson {
+        method: &'static str,
+        height: Option<u64>,
+        source: serde_json::Error,
+    },
+    /// A root of the data availability header could not be decoded
+    InvalidDah { height: u64, source: DahDecodeError },
+    /// The shares of a namespace could not be parsed
+    InvalidShares {
+        height: u64,
+        namespace: NamespaceId,
+        source: serde_json::Error,
+    },
+    /// The shares of a namespace don't match the proofs returned with them
+    InvalidSharesProof {
+        height: u64,
+        namespace: NamespaceId,
+        reason: &'static str,
+    },
+    /// The extended data square does not match the data availability header. Either its number of shares
+    /// doesn't match the width of the header, or the tree of `mismatched_row` doesn't have the committed root.
+    InconsistentEds {
+        height: u64,
+        expected_width: usize,
+        shares: usize,
+        mismatched_row: Option<usize>,
+    },
+    /// The pfb namespace could not be decoded, or its pfbs don't match the blobs of the rollup namespaces
+    InvalidPfb { height: u64, source: BoxError },
+    /// The proof of the pfbs paying for the block's blobs could not be built
+    ProofGeneration {
+        height: u64,
+        source: ProofGenerationError,
+    },
+    /// Two endpoints returned different headers for the same height
+    HeaderMismatch {
+        height: u64,
+        hash: tendermint::Hash,
+        other_hash: tendermint::Hash,
+    },
+    /// The header subscription ended while waiting for a block
+    SubscriptionClosed,
+}
+
+impl DaServiceError {
+    /// Wraps a failed RPC call, separating responses which couldn't be decoded from other failures
+    fn rpc(method: &'static str, height: Option<u64>, err: RpcError) -> Self {
+        match err {
+            RpcError::Permanent(JsonRpcError::ParseError(source)) => DaServiceError::InvalidJson {
+                method,
+                height,
+                source,
+            },
+            source => DaServiceError::Rpc {
+                method,
+                height,
+                source,
+            },
+        }
+    }
+}
+
+impl Display for DaServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DaServiceError::NotYetProduced(e) => write!(f, "DaServiceError::NotYetProduced({})", e),
+            DaServiceError::Rpc {
+                method,
+                height,
+                source,
+            } => write!(
+                f,
+                "DaServiceError::Rpc {{ method: {}, height: {:?}, source: {} }}",
+                method, height, source
+            ),
+            DaServiceError::InvalidJson {
+                method,
+                height,
+                source,
+            } => write!(
+                f,
+                "DaServiceError::InvalidJson {{ method: {}, height: {:?}, source: {} }}",
+                method, height, source
+            ),
+            DaServiceError::InvalidDah { height, source } => write!(
+                f,
+                "DaServiceError::InvalidDah {{ height: {}, source: {} }}",
+                height, source
+            ),
+            DaServiceError::InvalidShares {
+                height,
+                namespace,
+                source,
+            } => write!(
+                f,
+                "DaServiceError::InvalidShares {{ height: {}, namespace: 0x{}, source: {} }}",
+                height,
+                hex::encode(namespace),
+                source
+            ),
+            DaServiceError::InvalidSharesProof {
+                height,
+                namespace,
+                reason,
+            } => write!(
+                f,
+                "DaServiceError::InvalidSharesProof {{ height: {}, namespace: 0x{}, reason: {} }}",
+                height,
+                hex::encode(namespace),
+                reason
+            ),
+            DaServiceError::InconsistentEds {
+                height,
+                expected_width,
+                shares,
+                mismatched_row,
+            } => write!(
+                f,
+                "DaServiceError::InconsistentEds {{ height: {}, expected_width: {}, shares: {}, mismatched_row: {:?} }}",
+                height, expected_width, shares, mismatched_row
+            ),
+            DaServiceError::InvalidPfb { height, source } => write!(
+                f,
+                "DaServiceError::InvalidPfb {{ height: {}, source: {} }}",
+                height, source
+            ),
+            DaServiceError::ProofGeneration { height, source } => write!(
+                f,
+                "DaServiceError::ProofGeneration {{ height: {}, source: {} }}",
+                height, source
+            ),
+            DaServiceError::HeaderMismatch {
+                height,
+                hash,
+                other_hash,
+            } => write!(
+                f,
+                "DaServiceError::HeaderMismatch {{ height: {}, hash: {}, other_hash: {} }}",
+                height, hash, other_hash
+            ),
+            DaServiceError::SubscriptionClosed => f.write_str("DaServiceError::SubscriptionClosed"),
+        }
+    }
+}
+
+impl std::error::Error for DaServiceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DaServiceError::NotYetProduced(e) => Some(e),
+            DaServiceError::Rpc { source, .. } => Some(source),
+            DaServiceError::InvalidJson { source, .. } => Some(source),
+            DaServiceError::InvalidDah { source, .. } => Some(source),
+            DaServiceError::InvalidShares { source, .. } => Some(source),
+            DaServiceError::InvalidPfb { source, .. } => Some(source.as_ref()),
+            DaServiceError::ProofGeneration { source, .. } => Some(source),
+            DaServiceError::InvalidSharesProof { .. }
+            | DaServiceError::InconsistentEds { .. }
+            | DaServiceError::HeaderMismatch { .. }
+            | DaServiceError::SubscriptionClosed => None,
+        }
+    }
+}
+
+impl From<BlockNotYetProduced> for DaServiceError {
+    fn from(value: BlockNotYetProduced) -> Self {
+        DaServiceError::NotYetProduced(value)
+    }
 }
 
 impl CelestiaService {
-    pub fn with_client(client: HttpClient, nid: NamespaceId) -> Self {
+    /// Creates a service which uses the default request timeout and retry policy
+    pub fn with_client(client: HttpClient, namespaces: BTreeSet<NamespaceId>) -> Self {
+        Self::with_rpc_client(
+            RpcClient::new(
+                RpcTransport::Http(client),
+                Duration::from_millis(default_request_timeout_ms()),
+                RetryConfig::default(),
+            ),
+            namespaces,
+        )
+    }
+
+    pub fn with_rpc_client(client: RpcClient, namespaces: BTreeSet<NamespaceId>) -> Self {
         Self {
             client,
-            rollup_namespace: nid,
+            rollup_namespaces: namespaces,
+            head_poll_interval: Duration::from_millis(default_head_poll_interval_ms()),
+            known_head: Arc::new(AtomicU64::new(0)),
+            cache: None,
+            cross_check_headers: false,
+            prefetch_retry: default_prefetch_retry(),
         }
     }
+
+    /// Sets how the block streams retry a height whose fetch failed. Every attempt already retries its rpc calls
+    /// with the client's retry config, so the two multiply.
+    pub fn with_prefetch_retry(mut self, retry: RetryConfig) -> Self {
+        self.prefetch_retry = retry;
+        self
+    }
+
+    /// If enabled, every header is fetched from two different endpoints, and blocks whose headers differ
+    /// are rejected
+    pub fn with_header_cross_check(mut self, enabled: bool) -> Self {
+        self.cross_check_headers = enabled;
+        self
+    }
+
+    /// Caches fetched blocks on disk. The cache holds only blocks filtered for the service's namespaces.
+    pub fn with_block_cache(mut self, config: &BlockCacheConfig) -> Result<Self, BoxError> {
+        self.cache = Some(BlockCache::open(config, &self.rollup_namespaces)?);
+        Ok(self)
+    }
+
+    /// Returns the block at `height` if it has been cached
+    async fn cached_block(&self, height: u64) -> Option<FilteredCelestiaBlock> {
+        self.cache.as_ref()?.get(height).await
+    }
+
+    pub fn with_head_poll_interval(mut self, interval: Duration) -> Self {
+        self.head_poll_interval = interval;
+        self
+    }
+
+    /// Returns the height of the latest block produced by the node
+    pub async fn head_height(&self) -> Result<u64, DaServiceError> {
+        let head: ExtendedHeader = self
+            .client
+            .request("header.LocalHead", Vec::<u64>::new())
+            .await
+            .map_err(|e| DaServiceError::rpc("header.LocalHead", None, e))?;
+        let height = head.height();
+        self.known_head.fetch_max(height, Ordering::Relaxed);
+        Ok(height)
+    }
+
+    /// Returns a [`DaServiceError::NotYetProduced`] error if the chain has not reached `height`
+    async fn ensure_produced(&self, height: u64) -> Result<(), DaServiceError> {
+        if self.known_head.load(Ordering::Relaxed) >= height {
+            return Ok(());
+        }
+        let head = self.head_height().await?;
+        if head < height {
+            return Err(BlockNotYetProduced { height, head }.into());
+        }
+        Ok(())
+    }
+
+    /// Waits until the chain reaches `height`. Subscribes to new headers if the transport supports it, and
+    /// polls the node's head otherwise.
+    async fn wait_for_height(&self, height: u64) -> Result<(), DaServiceError> {
+        if self.client.supports_subscriptions() {
+            match self.wait_for_height_with_subscription(height).await {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!(
+                    "Header subscription failed with {}. Falling back to polling",
+                    e
+                ),
+            }
+        }
+        loop {
+            match self.ensure_produced(height).await {
+                Err(DaServiceError::NotYetProduced(_)) => {
+                    debug!("Waiting for block at height={} to be produced", height);
+                    tokio::time::sleep(self.head_poll_interval).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn wait_for_height_with_subscription(&self, height: u64) -> Result<(), DaServiceError> {
+        let mut headers = self
+            .client
+            .subscribe::<ExtendedHeader, _>(
+                "header.Subscribe",
+                Vec::<u64>::new(),
+                "header.Unsubscribe",
+            )
+            .await
+            .map_err(|e| DaServiceError::rpc("header.Subscribe", Some(height), e))?;
+        // Check the head only after subscribing, so that a block produced in between isn't missed
+        match self.ensure_produced(height).await {
+            Err(DaServiceError::NotYetProduced(_)) => {}
+            result => return result,
+        }
+        debug!("Waiting for block at height={} to be produced", height);
+        while let Some(header) = headers.next().await {
+            let head = header
+                .map_err(|e| {
+                    DaServiceError::rpc("header.Subscribe", Some(height), RpcError::classify(e))
+                })?
+                .height();
+            self.known_head.fetch_max(head, Ordering::Relaxed);
+            if head >= height {
+                return Ok(());
+            }
+        }
+        Err(DaServiceError::SubscriptionClosed)
+    }
+
+    /// Returns a stream of finalized blocks, starting at `start_height`. Each item becomes available once its
+    /// block has been produced. A failed height is retried with the prefetch retry config so that no block is
+    /// skipped. If it keeps failing, its error is yielded and the stream ends.
+    pub fn finalized_blocks(
+        &self,
+        start_height: u64,
+    ) -> impl Stream<Item = Result<FilteredCelestiaBlock, DaServiceError>> {
+        self.prefetched_blocks(start_height, 1)
+    }
+
+    /// Returns a stream of finalized blocks starting at `start_height`, like [`Self::finalized_blocks`], but
+    /// fetches up to `window` upcoming blocks concurrently. Blocks are still yielded in height order, and at
+    /// most `window` blocks are held in memory at once.
+    pub fn prefetched_blocks(
+        &self,
+        start_height: u64,
+        window: usize,
+    ) -> impl Stream<Item = Result<FilteredCelestiaBlock, DaServiceError>> {
+        let service = self.clone();
+        prefetch(
+            start_height,
+            window,
+            self.prefetch_retry.clone(),
+            move |height| service.get_finalized_at(height),
+        )
+    }
+
+    /// Fetches the block at `height`, which must already have been produced
+    async fn fetch_block(&self, height: u64) -> Result<FilteredCelestiaBlock, DaServiceError> {
+        let client = &self.client;
+        let rollup_namespaces = &self.rollup_namespaces;
+        let _span = span!(Level::TRACE, "fetching finalized block", height = height);
+        let started = Instant::now();
+        // Fetch the header and relevant shares via RPC
+        info!("Fetching header at height={}...", height);
+        let rpc_error =
+            |method: &'static str| move |e: RpcError| DaServiceError::rpc(method, Some(height), e);
+        let header = if self.cross_check_headers {
+            let (header, other) = client
+                .request_pair::<ExtendedHeader, _>("header.GetByHeight", vec![height])
+                .await
+                .map_err(rpc_error("header.GetByHeight"))?;
+            if let Some(other) = other {
+                ensure_same_header(height, &header, &other)?;
+            }
+            header
+        } else {
+            client
+                .request::<ExtendedHeader, _>("header.GetByHeight", vec![height])
+                .await
+                .map_err(rpc_error("header.GetByHeight"))?
+        };
+        debug!(header_result = ?header);
+        let dah: DataAvailabilityHeader = header
+            .dah
+            .clone()
+            .try_into()
+            .map_err(|source| DaServiceError::InvalidDah { height, source })?;
+
+        info!("Fetching shares...");
+        let (rollup_shares, tx_data) =
+            fetch_needed_shares_by_header(rollup_namespaces, client, height, &header.dah, &dah)
+                .await?;
+
+        info!("Fetching EDS...");
+        // Fail early with a clear error if the square is too large to download
+        client
+            .check_response_size(
+                "share.GetEDS",
+                ExtendedDataSquare::estimated_response_size(dah.row_roots.len()),
+            )
+            .map_err(rpc_error("share.GetEDS"))?;
+        // Fetch entire extended data square
+        let data_square = client
+            .request::<ExtendedDataSquare, _>("share.GetEDS", vec![&header.dah])
+            .await
+            .map_err(rpc_error("share.GetEDS"))?;
+        telemetry::record_eds_size(data_square.data_square.len());
+        // Split the square into rows once, and share them between all of the namespaces
+        let square_rows = check_eds(height, &data_square, &dah)?;
+
+        info!("Parsing namespaces...");
+        // Parse out all of the rows containing etxs
+        let etx_rows =
+            get_rows_containing_namespace(PFB_NAMESPACE, &dah, square_rows.iter().copied());
+        if tx_data.shares().is_empty() {
+            ensure_absent(height, PFB_NAMESPACE, &etx_rows)?;
+        }
+        // Parse out all of the rows containing data from each rollup namespace. If a namespace has no data,
+        // keep the row which proves its absence instead.
+        let mut rollup_data = Vec::with_capacity(rollup_namespaces.len());
+        for (&namespace, data) in rollup_namespaces.iter().zip(rollup_shares) {
+            let rows = match get_absence_row(namespace, &dah, &square_rows) {
+                Some(row) => vec![row],
+                None => get_rows_containing_namespace(namespace, &dah, square_rows.iter().copied()),
+            };
+            if data.shares().is_empty() {
+                ensure_absent(height, namespace, &rows)?;
+            }
+            rollup_data.push(RollupNamespaceData {
+                namespace,
+                data,
+                rows,
+            });
+        }
+
+        info!("Decoding pfb protofbufs...");
+        // Parse out the pfds and store them for later retrieval
+        let pfds = parse_pfb_namespace(tx_data)
+            .map_err(|source| DaServiceError::InvalidPfb { height, source })?;
+        let relevant_pfbs = RelevantPfb::filter(pfds, rollup_namespaces);
+        telemetry::record_relevant_pfbs(
+            relevant_pfbs.len(),
+            relevant_pfbs.iter().map(|pfb| pfb.blob_indices.len()).sum(),
+        );
+
+        let block = FilteredCelestiaBlock::new(
+            CelestiaHeader::new(dah, header.header.into()),
+            rollup_data,
+            relevant_pfbs,
+            etx_rows,
+        );
+        // The blobs are later attributed to the senders of their pfbs by position, so check that they line up
+        block
+            .check_blob_pfbs()
+            .map_err(|source| DaServiceError::InvalidPfb { height, source })?;
+        // The DaService methods which build proofs can't fail, so make sure that the proofs can be built now
+        check_correctness_proof(&block)
+            .map_err(|source| DaServiceError::ProofGeneration { height, source })?;
+        telemetry::record_block_fetch(started.elapsed());
+        if let Some(cache) = &self.cache {
+            // A failure to cache the block shouldn't fail the fetch
+            if let Err(e) = cache.insert(height, &block).await {
+                warn!("Failed to cache block at height={}: {}", height, e);
+            }
+        }
+        Ok(block)
+    }
 }
 
-/// Fetch the rollup namespace shares and etx data. Returns a tuple `(rollup_shares, etx_shares)`
-async fn fetch_needed_shares_by_header(
-    rollup_namespace: NamespaceId,
-    client: &HttpClient,
-    header: &serde_json::Value,
-) -> Result<(NamespaceGroup, NamespaceGroup), BoxError> {
-    let dah = header
-        .get("dah")
-        .ok_or(BoxError::msg("missing dah in block header"))?;
-    let rollup_namespace_str = base64::encode(rollup_namespace).into();
-    let rollup_shares_future = {
-        let params: Vec<&serde_json::Value> = vec![dah, &rollup_namespace_str];
-        client.request::<RpcNamespacedSharesResponse, _>("share.GetSharesByNamespace", params)
+/// Yields the result of `fetch` for consecutive heights starting at `start_height`, running up to `window` fetches
+/// concurrently. Results are yielded in height order. A failed height is retried with backoff before any later
+/// result is yielded, and once it has been retried `retry.max_retries` times its error is yielded and the stream
+/// ends.
+fn prefetch<T, E, F, Fut>(
+    start_height: u64,
+    window: usize,
+    retry: RetryConfig,
+    fetch: F,
+) -> impl Stream<Item = Result<T, E>>
+where
+    E: Display,
+    F: Fn(u64) -> Fut + Clone,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let window = window.max(1);
+    let fetch_after = move |height: u64, delay: Duration| {
+        let fetch = fetch.clone();
+        async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            (height, fetch(height).await)
+        }
     };
+    // The state is the next height to request, the number of times the oldest height has been retried, and the
+    // pending fetches. It is `None` once the stream has given up.
+    futures::stream::unfold(
+        Some((start_height, 0, FuturesOrdered::new())),
+        move |state| {
+            let fetch_after = fetch_after.clone();
+            let retry = retry.clone();
+            async move {
+                let (mut next_height, mut retries, mut pending) = state?;
+                loop {
+                    while pending.len() < window {
+                        pending.push_back(fetch_after(next_height, Duration::ZERO));
+                        next_height += 1;
+                    }
+                    let (height, result) =
+                        pending.next().await.expect("the pipeline is never empty");
+                    match result {
+                        Ok(block) => return Some((Ok(block), Some((next_height, 0, pending)))),
+                        Err(e) if retries < retry.max_retries => {
+                            let backoff = retry.backoff(retries);
+                            warn!(
+                                "Fetching block at height={} failed with {}. Retrying in {:?}",
+                                height, e, backoff
+                            );
+                            retries += 1;
+                            pending.push_front(fetch_after(height, backoff));
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Fetching block at height={} failed {} times. Giving up",
+                                height,
+                                retries + 1
+                            );
+                            return Some((Err(e), None));
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Checks that two endpoints returned the same header for `height`
+fn ensure_same_header(
+    height: u64,
+    header: &ExtendedHeader,
+    other: &ExtendedHeader,
+) -> Result<(), DaServiceError> {
+    let (hash, other_hash) = (header.header.hash(), other.header.hash());
+    if hash != other_hash {
+        return Err(DaServiceError::HeaderMismatch {
+            height,
+            hash,
+            other_hash,
+        });
+    }
+    Ok(())
+}
 
-    let etx_namespace_str = base64::encode(PFB_NAMESPACE).into();
-    let etx_shares_future = {
-        let params: Vec<&serde_json::Value> = vec![dah, &etx_namespace_str];
-        client.request::<RpcNamespacedSharesResponse, _>("share.GetSharesByNamespace", params)
+/// Fetch the shares of every rollup namespace and the etx data. Returns a tuple `(rollup_shares, etx_shares)`,
+/// where `rollup_shares` holds the shares of each namespace in the order of `rollup_namespaces`. The shares
+/// of each namespace are checked against the row roots of `dah` before they are returned.
+async fn fetch_needed_shares_by_header(
+    rollup_namespaces: &BTreeSet<NamespaceId>,
+    client: &RpcClient,
+    height: u64,
+    marshalled_dah: &MarshalledDataAvailabilityHeader,
+    dah: &DataAvailabilityHeader,
+) -> Result<(Vec<NamespaceGroup>, NamespaceGroup), DaServiceError> {
+    let fetch_shares = |namespace: NamespaceId| {
+        let params = rpc_params![marshalled_dah, base64::encode(namespace)];
+        async move {
+            let response = client
+                .request::<RpcNamespacedSharesResponse, _>("share.GetSharesByNamespace", params)
+                .await
+                .map_err(|e| match e {
+                    // Shares are parsed while the response is decoded
+                    RpcError::Permanent(JsonRpcError::ParseError(source)) => {
+                        DaServiceError::InvalidShares {
+                            height,
+                            namespace,
+                            source,
+                        }
+                    }
+                    e => DaServiceError::rpc("share.GetSharesByNamespace", Some(height), e),
+                })?;
+            verify_namespaced_shares(namespace, dah, response).map_err(|reason| {
+                DaServiceError::InvalidSharesProof {
+                    height,
+                    namespace,
+                    reason,
+                }
+            })
+        }
     };
 
-    let (rollup_shares_resp, etx_shares_resp) =
-        tokio::join!(rollup_shares_future, etx_shares_future);
-
-    let rollup_shares = NamespaceGroup::Sparse(
-        rollup_shares_resp?
-            .0
-            .unwrap_or_default()
-            .into_iter()
-            .flat_map(|resp| resp.shares)
-            .collect(),
-    );
-    let tx_data = NamespaceGroup::Compact(
-        etx_shares_resp?
-            .0
-            .unwrap_or_default()
-            .into_iter()
-            .flat_map(|resp| resp.shares)
-            .collect(),
-    );
+    // Request every namespace at once, and fail as soon as any of the requests fails
+    let rollup_shares_future =
+        futures::future::try_join_all(rollup_namespaces.iter().map(|&ns| fetch_shares(ns)));
+    let etx_shares_future = fetch_shares(PFB_NAMESPACE);
+    let (rollup_shares, etx_shares) =
+        futures::future::try_join(rollup_shares_future, etx_shares_future).await?;
+
+    let rollup_shares = rollup_shares
+        .into_iter()
+        .map(NamespaceGroup::Sparse)
+        .collect();
+    let tx_data = NamespaceGroup::Compact(etx_shares);
 
     Ok((rollup_shares, tx_data))
 }
 
+/// Checks the proof of each row returned by `share.GetSharesByNamespace` against the root of the matching row,
+/// and returns the shares of all of the rows. The node returns one entry for every row root whose namespace
+/// range contains `namespace`.
+///
+/// Since shares are sorted by namespace, the range of a row only contains a namespace which is present in the
+/// square if the row holds some of its shares. An empty row is therefore only valid as the single row claiming
+/// that the namespace is absent. The response has no leaf to check that claim against, so it must be checked
+/// against the full square with [`ensure_absent`].
+fn verify_namespaced_shares(
+    namespace: NamespaceId,
+    dah: &DataAvailabilityHeader,
+    response: RpcNamespacedSharesResponse,
+) -> Result<Vec<Share>, &'static str> {
+    let mut row_roots = dah.row_roots.iter().filter(|root| root.contains(namespace));
+    let rows = response.0.unwrap_or_default();
+    let row_count = rows.len();
+    let mut shares = Vec::new();
+    for row in rows {
+        let root = row_roots
+            .next()
+            .ok_or("more rows than row roots containing the namespace")?;
+        if row.shares.is_empty() {
+            if row_count > 1 {
+                return Err("empty row among several rows containing the namespace");
+            }
+            continue;
+        }
+        let proof: NamespaceProof<NamespacedSha2Hasher> = row
+            .proof
+            .try_into()
+            .map_err(|_| "proof node is not a valid cid")?;
+        let leaves: Vec<&[u8]> = row.shares.iter().map(Share::as_serialized).collect();
+        proof
+            .verify_complete_namespace(root, &leaves, namespace)
+            .map_err(|_| "invalid namespace proof")?;
+        shares.extend(row.shares);
+    }
+    if row_roots.next().is_some() {
+        return Err("missing rows containing the namespace");
+    }
+    Ok(shares)
+}
+
 /// Runtime configuration for the DA service
 #[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct DaServiceConfig {
-    /// The jwt used to authenticate with the Celestia rpc server
-    pub celestia_rpc_auth_token: String,
-    /// The address of the Celestia rpc server
+    /// The jwt used to authenticate with the Celestia rpc server. Leave all three auth options unset for
+    /// local nodes which don't require auth.
+    #[serde(default)]
+    pub celestia_rpc_auth_token: Option<String>,
+    /// A file containing the jwt, as an alternative to `celestia_rpc_auth_token`
+    #[serde(default)]
+    pub celestia_rpc_auth_token_file: Option<PathBuf>,
+    /// An environment variable containing the jwt, as an alternative to `celestia_rpc_auth_token`
+    #[serde(default)]
+    pub celestia_rpc_auth_token_env: Option<String>,
+    /// The address of the Celestia rpc server. A `ws://` or `wss://` address connects over a single websocket,
+    /// which is also used to subscribe to new headers.
     #[serde(default = "default_rpc_addr")]
     pub celestia_rpc_address: String,
-    /// The maximum size of a Celestia RPC response, in bytes
+    /// Additional Celestia rpc servers to fail over to when the primary is erroring or lagging. All of them
+    /// share the auth and TLS settings of the primary.
+    #[serde(default)]
+    pub celestia_rpc_fallback_addresses: Vec<String>,
+    /// How requests are spread across the rpc servers
+    #[serde(default)]
+    pub failover: FailoverConfig,
+    /// Fetch each header from two different rpc servers and fail if their hashes differ
+    #[serde(default)]
+    pub cross_check_headers: bool,
+    /// Additional headers to send with every request
+    #[serde(default)]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Which certificates to trust for `https://` and `wss://` addresses
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// The maximum size of a Celestia RPC request, in bytes
+    #[serde(default = "default_max_request_size")]
+    pub max_celestia_request_body_size: u32,
+    /// The maximum size of a Celestia RPC response, in bytes. Must be large enough to hold the extended data
+    /// square of every block.
     #[serde(default = "default_max_response_size")]
     pub max_celestia_response_body_size: u32,
+    /// The maximum time to wait for a single Celestia RPC call, in milliseconds
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    /// The maximum time to wait for a websocket connection to be established, in milliseconds
+    #[serde(default = "default_connection_timeout_ms")]
+    pub connection_timeout_ms: u64,
+    /// How to retry failed reads from the Celestia rpc server
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// How the block streams retry a height whose fetch failed. Each attempt already retries its rpc calls with
+    /// `retry`, so the attempts multiply, and most errors which outlast those retries (such as invalid data from
+    /// the node) won't be fixed by fetching the block again. The default therefore only retries twice, after
+    /// waiting long enough for a transient outage to pass.
+    #[serde(default = "default_prefetch_retry")]
+    pub prefetch_retry: RetryConfig,
+    /// How often to poll for new blocks while waiting for a block to be produced, in milliseconds
+    #[serde(default = "default_head_poll_interval_ms")]
+    pub head_poll_interval_ms: u64,
+    /// An optional on-disk cache of fetched blocks
+    #[serde(default)]
+    pub block_cache: Option<BlockCacheConfig>,
+}
+
+/// TLS settings for connections to the Celestia rpc server
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// The root certificates to trust
+    pub certificate_store: CertificateStoreConfig,
+    /// Not supported: the rpc client can only trust the native or webpki roots, so setting this is an error
+    /// rather than being silently ignored. To trust a private CA, add it to the operating system's certificate
+    /// store and use the `native` store.
+    pub ca_certificate_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateStoreConfig {
+    /// The operating system's root certificates
+    Native,
+    /// The Mozilla root certificates bundled with the client
+    WebPki,
+}
+
+impl Default for CertificateStoreConfig {
+    fn default() -> Self {
+        CertificateStoreConfig::Native
+    }
 }
 
 fn default_rpc_addr() -> String {
     "http://localhost:11111/".into()
 }
 
+fn default_max_request_size() -> u32 {
+    1024 * 1024 * 10 // 10 MB
+}
+
 fn default_max_response_size() -> u32 {
     1024 * 1024 * 100 // 100 MB
 }
 
+fn default_request_timeout_ms() -> u64 {
+    60_000 // 1 minute
+}
+
+fn default_connection_timeout_ms() -> u64 {
+    10_000 // 10 seconds
+}
+
+fn default_head_poll_interval_ms() -> u64 {
+    1_000 // 1 second
+}
+
+fn default_prefetch_retry() -> RetryConfig {
+    RetryConfig {
+        max_retries: 2,
+        initial_backoff_ms: 5_000,
+        max_backoff_ms: 10_000,
+    }
+}
+
+impl DaServiceConfig {
+    /// Reads the auth token from whichever source is configured. Returns `None` if no auth is configured.
+    pub fn auth_token(&self) -> Result<Option<String>, BoxError> {
+        self.auth_token_from(|var| std::env::var(var))
+    }
+
+    /// Reads the auth token, looking up environment variables through `env`
+    fn auth_token_from(
+        &self,
+        env: impl FnOnce(&str) -> Result<String, std::env::VarError>,
+    ) -> Result<Option<String>, BoxError> {
+        let sources = [
+            self.celestia_rpc_auth_token.is_some(),
+            self.celestia_rpc_auth_token_file.is_some(),
+            self.celestia_rpc_auth_token_env.is_some(),
+        ];
+        ensure!(
+            sources.iter().filter(|&&is_set| is_set).count() <= 1,
+            "at most one of celestia_rpc_auth_token, celestia_rpc_auth_token_file and celestia_rpc_auth_token_env may be set"
+        );
+
+        let token = if let Some(token) = &self.celestia_rpc_auth_token {
+            token.clone()
+        } else if let Some(path) = &self.celestia_rpc_auth_token_file {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read auth token from {}", path.display()))?
+        } else if let Some(var) = &self.celestia_rpc_auth_token_env {
+            env(var).with_context(|| format!("failed to read auth token from ${}", var))?
+        } else {
+            return Ok(None);
+        };
+        let token = token.trim();
+        ensure!(!token.is_empty(), "the auth token is empty");
+        Ok(Some(token.to_string()))
+    }
+
+    /// Builds the headers sent with every request, including the auth header
+    pub fn headers(&self) -> Result<HeaderMap, BoxError> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = self.auth_token()? {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .context("the auth token is not a valid header value")?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        for (name, value) in self.extra_headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {:?}", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {}", name))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    fn certificate_store(&self) -> Result<CertificateStore, BoxError> {
+        ensure!(
+            self.tls.ca_certificate_file.is_none(),
+            "ca_certificate_file is not supported; add the CA to the system's certificate store and use the native store"
+        );
+        Ok(match self.tls.certificate_store {
+            CertificateStoreConfig::Native => CertificateStore::Native,
+            CertificateStoreConfig::WebPki => CertificateStore::WebPki,
+        })
+    }
+}
+
+impl CelestiaService {
+    /// Creates a service from its runtime configuration, failing on invalid settings such as a malformed
+    /// auth token or address
+    pub fn from_config(
+        config: DaServiceConfig,
+        chain_params: RollupParams,
+    ) -> Result<Self, BoxError> {
+        let options = TransportOptions {
+            headers: config.headers()?,
+            max_request_size: config.max_celestia_request_body_size,
+            max_response_size: config.max_celestia_response_body_size,
+            request_timeout: Duration::from_millis(config.request_timeout_ms),
+            connection_timeout: Duration::from_millis(config.connection_timeout_ms),
+            certificate_store: config.certificate_store()?,
+        };
+        let transports = std::iter::once(&config.celestia_rpc_address)
+            .chain(config.celestia_rpc_fallback_addresses.iter())
+            .map(|address| RpcTransport::from_address(address, options.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        ensure!(
+            !config.cross_check_headers || transports.len() > 1,
+            "cross_check_headers requires at least one fallback address"
+        );
+
+        let client = RpcClient::with_failover(
+            transports,
+            Duration::from_millis(config.request_timeout_ms),
+            config.retry,
+            config.failover,
+        )?
+        .with_max_response_size(config.max_celestia_response_body_size);
+        let service = Self::with_rpc_client(client, chain_params.namespaces)
+            .with_head_poll_interval(Duration::from_millis(config.head_poll_interval_ms))
+            .with_header_cross_check(config.cross_check_headers)
+            .with_prefetch_retry(config.prefetch_retry);
+        Ok(match config.block_cache {
+            Some(cache_config) => service.with_block_cache(&cache_config)?,
+            None => service,
+        })
+    }
+}
+
 impl DaService for CelestiaService {
     type RuntimeConfig = DaServiceConfig;
 
@@ -115,120 +958,45 @@ impl DaService for CelestiaService {
 
     type Future<T> = Pin<Box<dyn Future<Output = Result<T, Self::Error>>>>;
 
-    type Error = BoxError;
+    type Error = DaServiceError;
 
+    /// Creates the service, panicking if the configuration is invalid. Use [`CelestiaService::from_config`]
+    /// to handle configuration errors instead.
     fn new(config: Self::RuntimeConfig, chain_params: RollupParams) -> Self {
-        let client = {
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                "Authorization",
-                format!("Bearer {}", config.celestia_rpc_auth_token)
-                    .parse()
-                    .unwrap(),
-            );
-            jsonrpsee::http_client::HttpClientBuilder::default()
-                .set_headers(headers)
-                .max_request_body_size(config.max_celestia_response_body_size) // 100 MB
-                .build(&config.celestia_rpc_address)
-        }
-        .expect("Client initialization is valid");
-
-        Self::with_client(client, chain_params.namespace)
+        Self::from_config(config, chain_params)
+            .unwrap_or_else(|e| panic!("Invalid DA service configuration: {:#}", e))
     }
 
     fn get_finalized_at(&self, height: u64) -> Self::Future<Self::FilteredBlock> {
-        let client = self.client.clone();
-        let rollup_namespace = self.rollup_namespace.clone();
+        let service = self.clone();
         Box::pin(async move {
-            let _span = span!(Level::TRACE, "fetching finalized block", height = height);
-            // Fetch the header and relevant shares via RPC
-            info!("Fetching header at height={}...", height);
-            let header = client
-                .request::<serde_json::Value, _>("header.GetByHeight", vec![height])
-                .await?;
-            debug!(header_result = ?header);
-            info!("Fetching shares...");
-            let (rollup_shares, tx_data) =
-                fetch_needed_shares_by_header(rollup_namespace, &client, &header).await?;
-
-            info!("Fetching EDS...");
-            // Fetch entire extended data square
-            let data_square = client
-                .request::<ExtendedDataSquare, _>(
-                    "share.GetEDS",
-                    vec![header
-                        .get("dah")
-                        .ok_or(BoxError::msg("missing 'dah' in block header"))?],
-                )
-                .await?;
-
-            let unmarshalled_header: CelestiaHeaderResponse = serde_json::from_value(header)?;
-            let dah: DataAvailabilityHeader = unmarshalled_header.dah.try_into()?;
-            info!("Parsing namespaces...");
-            // Parse out all of the rows containing etxs
-            let etx_rows =
-                get_rows_containing_namespace(PFB_NAMESPACE, &dah, data_square.rows()?.into_iter())
-                    .await?;
-            // Parse out all of the rows containing rollup data
-            let rollup_rows = get_rows_containing_namespace(
-                rollup_namespace,
-                &dah,
-                data_square.rows()?.into_iter(),
-            )
-            .await?;
-
-            info!("Decoding pfb protofbufs...");
-            // Parse out the pfds and store them for later retrieval
-            let pfds = parse_pfb_namespace(tx_data)?;
-            let mut pfd_map = HashMap::new();
-            for tx in pfds {
-                for (idx, nid) in tx.0.namespace_ids.iter().enumerate() {
-                    if nid == &rollup_namespace.0[..] {
-                        // TODO: Retool this map to avoid cloning txs
-                        pfd_map.insert(tx.0.share_commitments[idx].clone(), tx.clone());
-                    }
-                }
+            if let Some(block) = service.cached_block(height).await {
+                return Ok(block);
             }
-
-            let filtered_block = FilteredCelestiaBlock {
-                header: CelestiaHeader::new(dah, unmarshalled_header.header.into()),
-                rollup_data: rollup_shares,
-                relevant_pfbs: pfd_map,
-                rollup_rows,
-                pfb_rows: etx_rows,
-            };
-
-            Ok::<Self::FilteredBlock, BoxError>(filtered_block)
+            // Celestia blocks are final as soon as they are produced, so it's enough to wait for the block
+            service.wait_for_height(height).await?;
+            service.fetch_block(height).await
         })
     }
 
+    /// Fetches the block at `height` without waiting. Fails with [`DaServiceError::NotYetProduced`] if the
+    /// block does not exist yet.
     fn get_block_at(&self, height: u64) -> Self::Future<Self::FilteredBlock> {
-        self.get_finalized_at(height)
+        let service = self.clone();
+        Box::pin(async move {
+            if let Some(block) = service.cached_block(height).await {
+                return Ok(block);
+            }
+            service.ensure_produced(height).await?;
+            service.fetch_block(height).await
+        })
     }
 
     fn extract_relevant_txs(
         &self,
         block: Self::FilteredBlock,
     ) -> Vec<<Self::Spec as sovereign_sdk::da::DaSpec>::BlobTransaction> {
-        let mut output = Vec::new();
-        for blob in block.rollup_data.blobs() {
-            let commitment =
-                recreate_commitment(block.square_size(), blob.clone()).expect("blob must be valid");
-            let sender = block
-                .relevant_pfbs
-                .get(&commitment[..])
-                .expect("blob must be relevant")
-                .0
-                .signer
-                .clone();
-
-            let blob_tx = BlobWithSender {
-                blob: blob.into(),
-                sender: CelestiaAddress(sender.as_bytes().to_vec()),
-            };
-            output.push(blob_tx)
-        }
-        output
+        relevant_txs(&block)
     }
 
     fn extract_relevant_txs_with_proof(
@@ -239,10 +1007,10 @@ impl DaService for CelestiaService {
         <Self::Spec as sovereign_sdk::da::DaSpec>::InclusionMultiProof,
         <Self::Spec as sovereign_sdk::da::DaSpec>::CompletenessProof,
     ) {
-        let relevant_txs = self.extract_relevant_txs(block.clone());
-        let etx_proofs = CorrectnessProof::for_block(&block, &relevant_txs);
-        let rollup_row_proofs =
-            CompletenessProof::from_filtered_block(&block, self.rollup_namespace);
+        let relevant_txs = relevant_txs(&block);
+        let etx_proofs = CorrectnessProof::for_block(&block, &relevant_txs)
+            .expect("proofs are checked to be buildable when the block is fetched");
+        let rollup_row_proofs = CompletenessProof::from_filtered_block(&block);
 
         (relevant_txs, etx_proofs.0, rollup_row_proofs.0)
     }
@@ -254,19 +1022,67 @@ impl DaService for CelestiaService {
         // Take ownership of the blob so that the future is 'static.
         let blob = blob.to_vec();
         Box::pin(async move {
+            // Submitting a transaction is not idempotent, so it is never retried
             let _response = client
-                .request::<serde_json::Value, _>("state.SubmitTx", vec![blob])
-                .await?;
-            Ok::<(), BoxError>(())
+                .request_once::<serde_json::Value, _>("state.SubmitTx", vec![blob])
+                .await
+                .map_err(|e| DaServiceError::rpc("state.SubmitTx", None, e))?;
+            Ok::<(), DaServiceError>(())
         })
     }
 }
 
-async fn get_rows_containing_namespace(
+/// Extracts the blobs of every rollup namespace, attributing each of them to the sender of its pfb
+fn relevant_txs(block: &FilteredCelestiaBlock) -> Vec<BlobWithSender> {
+    let mut output = Vec::new();
+    let ods_width = block.square_size() / 2;
+    for namespace_data in block.rollup_data.iter() {
+        let namespace = namespace_data.namespace;
+        let blob_pfbs = block.namespace_blob_pfbs(namespace);
+        let namespace_start = block.rollup_namespace_start(namespace);
+        // The offset of the current blob from the start of its namespace
+        let mut share_offset = 0;
+        // Blobs are stored in the same order as their pfbs, so match them up positionally. This attributes
+        // duplicate blobs to the correct senders. The order was checked when the block was fetched.
+        for (blob, (relevant_pfb, _)) in namespace_data.data.blobs().zip(blob_pfbs) {
+            let commitment = recreate_commitment(block.square_size(), blob.clone())
+                .expect("blobs are checked against their pfbs when the block is fetched");
+            let sender = &relevant_pfb.pfb.signer;
+            let start_share_idx = namespace_start
+                .expect("rollup rows must contain the rollup namespace")
+                + share_offset;
+            share_offset += blob.0.len();
+
+            let blob_tx = BlobWithSender {
+                metadata: BlobMetadata::new(
+                    namespace,
+                    start_share_idx,
+                    ods_width,
+                    commitment,
+                    relevant_pfb.tx_hash,
+                ),
+                blob: blob.into(),
+                sender: CelestiaAddress(sender.as_bytes().to_vec()),
+            };
+            output.push(blob_tx)
+        }
+    }
+    output
+}
+
+/// Checks that the proof of the pfbs paying for the block's blobs can be built, so that
+/// [`DaService::extract_relevant_txs_with_proof`] doesn't fail on the block
+pub(crate) fn check_correctness_proof(
+    block: &FilteredCelestiaBlock,
+) -> Result<(), ProofGenerationError> {
+    CorrectnessProof::for_block(block, &relevant_txs(block)).map(|_| ())
+}
+
+fn get_rows_containing_namespace<'a>(
     nid: NamespaceId,
     dah: &DataAvailabilityHeader,
-    data_square_rows: impl Iterator<Item = &[Share]>,
-) -> Result<Vec<Row>, BoxError> {
+    data_square_rows: impl Iterator<Item = &'a [Share]>,
+) -> Vec<Row> {
     let mut output = vec![];
 
     for (row, root) in data_square_rows.zip(dah.row_roots.iter()) {
@@ -277,14 +1093,114 @@ async fn get_rows_containing_namespace(
             })
         }
     }
-    Ok(output)
+    output
+}
+
+/// Checks that a namespace for which the node returned no shares has no shares in `rows`, which must have been
+/// checked against their roots
+fn ensure_absent(height: u64, namespace: NamespaceId, rows: &[Row]) -> Result<(), DaServiceError> {
+    let is_present = rows.iter().any(|row| {
+        let ods_width = row.shares.len() / 2;
+        row.shares[..ods_width]
+            .iter()
+            .any(|share| share.namespace() == namespace)
+    });
+    if is_present {
+        return Err(DaServiceError::InvalidSharesProof {
+            height,
+            namespace,
+            reason: "the namespace has shares in a row which was returned empty",
+        });
+    }
+    Ok(())
+}
+
+/// Checks the extended data square against the data availability header, and splits it into rows. Every row is
+/// checked against its root, so that a corrupted square is rejected here rather than causing a panic once the
+/// rows of the block are merklized.
+fn check_eds<'a>(
+    height: u64,
+    eds: &'a ExtendedDataSquare,
+    dah: &DataAvailabilityHeader,
+) -> Result<Vec<&'a [Share]>, DaServiceError> {
+    let expected_width = dah.row_roots.len();
+    let inconsistent = |mismatched_row| DaServiceError::InconsistentEds {
+        height,
+        expected_width,
+        shares: eds.data_square.len(),
+        mismatched_row,
+    };
+    if eds.data_square.len() != expected_width * expected_width {
+        return Err(inconsistent(None));
+    }
+    let rows = eds.rows().expect("the square was checked against the dah");
+
+    let has_root = |(shares, root): (&&[Share], &NamespacedHash)| {
+        Row::tree_of(shares).map(|mut tree| tree.root()).as_ref() == Some(root)
+    };
+    #[cfg(feature = "parallel")]
+    let mismatched_row = {
+        use rayon::prelude::*;
+        rows.par_iter()
+            .zip(dah.row_roots.par_iter())
+            .position_first(|row| !has_root(row))
+    };
+    #[cfg(not(feature = "parallel"))]
+    let mismatched_row = rows
+        .iter()
+        .zip(dah.row_roots.iter())
+        .position(|row| !has_root(row));
+    match mismatched_row {
+        Some(row) => Err(inconsistent(Some(row))),
+        None => Ok(rows),
+    }
+}
+
+/// Returns the row in which shares of `nid` would have been stored, if the namespace is absent from the square
+fn get_absence_row(
+    nid: NamespaceId,
+    dah: &DataAvailabilityHeader,
+    data_square_rows: &[&[Share]],
+) -> Option<Row> {
+    let row_idx = dah.absence_row(nid)?;
+    Some(Row {
+        shares: data_square_rows.get(row_idx)?.to_vec(),
+        root: dah.row_roots[row_idx].clone(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+    use jsonrpsee::core::{client::CertificateStore, Error as JsonRpcError};
+
+    use nmt_rs::{NamespaceId, NamespaceProof, NamespacedHash};
+    use sovereign_sdk::services::da::DaService;
+
+    use super::{
+        check_eds, ensure_absent, prefetch, verify_namespaced_shares, BlockNotYetProduced,
+        CelestiaService, DaServiceConfig, DaServiceError, TlsConfig,
+    };
     use crate::{
+        cid::ns_hash_to_cid,
         parse_pfb_namespace,
+        rpc::{FailoverConfig, RetryConfig, RpcError},
         shares::{NamespaceGroup, Share},
+        test_utils::{
+            forked_header_responses, header_json, header_responses, single_blob_block,
+            BlockBuilder, MockNode, TestBlob, TEST_NAMESPACE,
+        },
+        types::{ExtendedDataSquare, FilteredCelestiaBlock, Row, RpcNamespacedSharesResponse},
+        verifier::{RollupParams, PFB_NAMESPACE},
     };
 
     const SERIALIZED_PFB_SHARES: &'static str = r#"["AAAAAAAAAAQBAAABRQAAABHDAgq3AgqKAQqHAQogL2NlbGVzdGlhLmJsb2IudjEuTXNnUGF5Rm9yQmxvYnMSYwovY2VsZXN0aWExemZ2cnJmYXE5dWQ2Zzl0NGt6bXNscGYyNHlzYXhxZm56ZWU1dzkSCHNvdi10ZXN0GgEoIiCB8FoaUuOPrX2wFBbl4MnWY3qE72tns7sSY8xyHnQtr0IBABJmClAKRgofL2Nvc21vcy5jcnlwdG8uc2VjcDI1NmsxLlB1YktleRIjCiEDmXaTf6RVIgUVdG0XZ6bqecEn8jWeAi+LjzTis5QZdd4SBAoCCAEYARISCgwKBHV0aWESBDIwMDAQgPEEGkAhq2CzD1DqxsVXIriANXYyLAmJlnnt8YTNXiwHgMQQGUbl65QUe37UhnbNVrOzDVYK/nQV9TgI+5NetB2JbIz6EgEBGgRJTkRYAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]"#;
@@ -319,4 +1235,479 @@ mod tests {
 
         assert!(blobs.next().is_none());
     }
+
+    #[test]
+    fn test_config_defaults() {
+        let config: DaServiceConfig =
+            serde_json::from_str(r#"{"celestia_rpc_auth_token": "token"}"#)
+                .expect("config must deserialize");
+        assert_eq!(config.retry, RetryConfig::default());
+        assert_eq!(config.request_timeout_ms, 60_000);
+        assert_eq!(config.max_celestia_request_body_size, 10 * 1024 * 1024);
+        assert_eq!(config.max_celestia_response_body_size, 100 * 1024 * 1024);
+        assert_eq!(config.head_poll_interval_ms, 1_000);
+        assert_eq!(config.block_cache, None);
+        assert_eq!(config.tls, TlsConfig::default());
+        assert!(config.celestia_rpc_fallback_addresses.is_empty());
+        assert_eq!(config.failover, FailoverConfig::default());
+        assert!(!config.cross_check_headers);
+        assert_eq!(config.auth_token().unwrap(), Some("token".to_string()));
+    }
+
+    #[test]
+    fn test_config_auth() {
+        let config = |json: &str| -> DaServiceConfig {
+            serde_json::from_str(json).expect("config must deserialize")
+        };
+        // Local nodes don't need a token
+        let no_auth = config("{}");
+        assert_eq!(no_auth.auth_token().unwrap(), None);
+        assert!(no_auth.headers().unwrap().is_empty());
+
+        // The environment is injected, since setting a real variable would race with other tests
+        let env_auth = config(r#"{"celestia_rpc_auth_token_env": "JUPITER_TEST_AUTH_TOKEN"}"#);
+        let env = |var: &str| {
+            assert_eq!(var, "JUPITER_TEST_AUTH_TOKEN");
+            Ok("  env-token\n".to_string())
+        };
+        assert_eq!(
+            env_auth.auth_token_from(env).unwrap(),
+            Some("env-token".to_string())
+        );
+        assert!(env_auth
+            .auth_token_from(|_| Err(std::env::VarError::NotPresent))
+            .is_err());
+
+        let both = config(
+            r#"{"celestia_rpc_auth_token": "token", "celestia_rpc_auth_token_env": "JUPITER_TEST_AUTH_TOKEN"}"#,
+        );
+        assert!(both.auth_token().is_err());
+
+        // Malformed tokens and headers are reported instead of panicking
+        assert!(config(r#"{"celestia_rpc_auth_token": "bad\ntoken"}"#)
+            .headers()
+            .is_err());
+        assert!(config(r#"{"extra_headers": {"bad header": "value"}}"#)
+            .headers()
+            .is_err());
+        let headers = config(r#"{"extra_headers": {"x-api-key": "key"}}"#)
+            .headers()
+            .unwrap();
+        assert_eq!(headers.get("x-api-key").unwrap(), "key");
+    }
+
+    #[test]
+    fn test_check_eds() {
+        let (block, _, eds) = BlockBuilder::new(2)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![1u8; 600]))
+            .build_with_eds(&BTreeSet::from([TEST_NAMESPACE]));
+        let dah = &block.header.dah;
+        assert_eq!(check_eds(5, &eds, dah).unwrap().len(), 4);
+        let mismatched_row = |eds| match check_eds(5, &eds, dah) {
+            Err(DaServiceError::InconsistentEds {
+                height: 5,
+                expected_width: 4,
+                shares,
+                mismatched_row,
+            }) => (shares, mismatched_row),
+            other => panic!("expected an inconsistent eds, got {:?}", other),
+        };
+
+        let mut short = eds.clone();
+        short.data_square.pop();
+        assert_eq!(mismatched_row(short), (15, None));
+
+        // Corrupt the second share of the blob, keeping the number of shares the same
+        let mut corrupted = eds.clone();
+        let idx = corrupted
+            .data_square
+            .iter()
+            .rposition(|share| share.namespace() == TEST_NAMESPACE)
+            .unwrap();
+        let mut raw = corrupted.data_square[idx].as_serialized().to_vec();
+        raw[100] ^= 1;
+        corrupted.data_square[idx] = Share::new(raw.into());
+        assert_eq!(mismatched_row(corrupted), (16, Some(idx / 4)));
+
+        // Shares whose namespaces are out of order are reported the same way, rather than panicking
+        let mut swapped = eds.clone();
+        swapped.data_square.swap(0, 1);
+        assert_eq!(mismatched_row(swapped), (16, Some(0)));
+    }
+
+    #[tokio::test]
+    async fn test_header_cross_check_mismatch() {
+        let honest = MockNode::start(header_responses(10)).await;
+        let forked = MockNode::start(forked_header_responses(10)).await;
+        let service = |fallback: &MockNode| {
+            let config: DaServiceConfig = serde_json::from_value(serde_json::json!({
+                "celestia_rpc_address": honest.url,
+                "celestia_rpc_fallback_addresses": [fallback.url],
+                "cross_check_headers": true,
+                "retry": {"max_retries": 0},
+            }))
+            .expect("config must deserialize");
+            let params = RollupParams {
+                namespaces: BTreeSet::from([TEST_NAMESPACE]),
+            };
+            CelestiaService::from_config(config, params).unwrap()
+        };
+
+        assert!(matches!(
+            service(&forked).fetch_block(5).await,
+            Err(DaServiceError::HeaderMismatch { height: 5, .. })
+        ));
+        assert_eq!(honest.calls("header.GetByHeight"), 1);
+        assert_eq!(forked.calls("header.GetByHeight"), 1);
+
+        // Matching headers pass the check, and the block fetch moves on to the shares
+        let fetched = service(&honest).fetch_block(5).await;
+        assert!(!matches!(
+            fetched,
+            Err(DaServiceError::HeaderMismatch { .. })
+        ));
+        assert_eq!(honest.calls("header.GetByHeight"), 3);
+    }
+
+    #[test]
+    fn test_config_tls() {
+        let config = |json: &str| -> DaServiceConfig {
+            serde_json::from_str(json).expect("config must deserialize")
+        };
+        assert!(matches!(
+            config("{}").certificate_store(),
+            Ok(CertificateStore::Native)
+        ));
+        assert!(matches!(
+            config(r#"{"tls": {"certificate_store": "web_pki"}}"#).certificate_store(),
+            Ok(CertificateStore::WebPki)
+        ));
+        // A custom CA bundle can't be passed to the client, so it's rejected instead of being ignored
+        assert!(
+            config(r#"{"tls": {"ca_certificate_file": "/etc/ssl/ca.pem"}}"#)
+                .certificate_store()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        let parse_error = serde_json::from_str::<u64>("not json").unwrap_err();
+        let err = DaServiceError::rpc(
+            "share.GetEDS",
+            Some(7),
+            RpcError::Permanent(JsonRpcError::ParseError(parse_error)),
+        );
+        assert!(matches!(
+            err,
+            DaServiceError::InvalidJson {
+                method: "share.GetEDS",
+                height: Some(7),
+                ..
+            }
+        ));
+
+        let err = DaServiceError::rpc(
+            "header.LocalHead",
+            None,
+            RpcError::Retryable(JsonRpcError::RequestTimeout),
+        );
+        assert!(matches!(err, DaServiceError::Rpc { height: None, .. }));
+        assert!(err
+            .to_string()
+            .starts_with("DaServiceError::Rpc { method: header.LocalHead, height: None"));
+
+        let err: DaServiceError = BlockNotYetProduced {
+            height: 10,
+            head: 8,
+        }
+        .into();
+        assert_eq!(
+            err.to_string(),
+            "DaServiceError::NotYetProduced(BlockNotYetProduced { height: 10, head: 8 })"
+        );
+    }
+
+    /// Builds the `share.GetSharesByNamespace` response which a node would return for the rollup namespace
+    fn namespaced_shares_response(block: &FilteredCelestiaBlock) -> serde_json::Value {
+        let rows = &block.rollup_namespace(TEST_NAMESPACE).unwrap().rows;
+        shares_response(rows, TEST_NAMESPACE)
+    }
+
+    /// Builds the `share.GetSharesByNamespace` response for a namespace which is present in each of `rows`
+    fn shares_response(rows: &[Row], namespace: NamespaceId) -> serde_json::Value {
+        let rows: Vec<serde_json::Value> = rows
+            .iter()
+            .map(|row| {
+                let (leaves, proof) = row.merklized().get_namespace_with_proof(namespace);
+                let proof = match proof {
+                    NamespaceProof::PresenceProof { proof, .. } => proof,
+                    NamespaceProof::AbsenceProof { .. } => panic!("namespace must be present"),
+                };
+                let nodes: Vec<serde_json::Value> = proof
+                    .siblings
+                    .iter()
+                    .map(|node| serde_json::json!({ "/": ns_hash_to_cid(node) }))
+                    .collect();
+                serde_json::json!({
+                    "Proof": {
+                        "Start": proof.start_idx,
+                        "End": proof.start_idx as usize + leaves.len(),
+                        "Nodes": nodes,
+                    },
+                    "Shares": leaves.iter().map(base64::encode).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        serde_json::Value::Array(rows)
+    }
+
+    /// Responds to calls to a [`MockNode`] like a node serving `block`, whose square is `eds`
+    fn block_responses(
+        block: &FilteredCelestiaBlock,
+        eds: &ExtendedDataSquare,
+    ) -> impl Fn(&str, &serde_json::Value) -> Option<serde_json::Value> + Send + Sync + 'static
+    {
+        let dah = &block.header.dah;
+        let encode_roots = |roots: &[NamespacedHash]| -> Vec<String> {
+            roots.iter().map(|root| base64::encode(root.0)).collect()
+        };
+        let mut header = header_json(block.header.header.height().unwrap());
+        header["header"]["data_hash"] =
+            hex::encode_upper(block.header.header.data_hash.as_ref().unwrap().0).into();
+        header["dah"] = serde_json::json!({
+            "row_roots": encode_roots(&dah.row_roots),
+            "column_roots": encode_roots(&dah.column_roots),
+        });
+        let pfb_shares = shares_response(&block.pfb_rows, PFB_NAMESPACE);
+        let rollup_shares = namespaced_shares_response(block);
+        let eds = serde_json::json!({
+            "data_square": eds
+                .data_square
+                .iter()
+                .map(|share| base64::encode(share.as_serialized()))
+                .collect::<Vec<_>>(),
+            "codec": eds.codec,
+        });
+        let pfb_namespace = base64::encode(PFB_NAMESPACE);
+        move |method: &str, params: &serde_json::Value| match method {
+            "header.GetByHeight" => Some(header.clone()),
+            "share.GetSharesByNamespace" if params[1] == pfb_namespace.as_str() => {
+                Some(pfb_shares.clone())
+            }
+            "share.GetSharesByNamespace" => Some(rollup_shares.clone()),
+            "share.GetEDS" => Some(eds.clone()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blob_without_pfb_is_rejected() {
+        let service = |node: &MockNode| {
+            let config: DaServiceConfig = serde_json::from_value(serde_json::json!({
+                "celestia_rpc_address": node.url,
+                "retry": {"max_retries": 0},
+            }))
+            .expect("config must deserialize");
+            let params = RollupParams {
+                namespaces: BTreeSet::from([TEST_NAMESPACE]),
+            };
+            CelestiaService::from_config(config, params).unwrap()
+        };
+        let builder =
+            || BlockBuilder::new(8).with_blob(TestBlob::rollup("celestia1alice", vec![1u8; 600]));
+
+        let (block, txs, eds) = builder().build_with_eds(&BTreeSet::from([TEST_NAMESPACE]));
+        let height = block.header.header.height().unwrap();
+        let node = MockNode::start(block_responses(&block, &eds)).await;
+        let fetched = service(&node).fetch_block(height).await.unwrap();
+        assert_eq!(fetched, block);
+        let (relevant_txs, _, _) = service(&node).extract_relevant_txs_with_proof(fetched);
+        assert_eq!(relevant_txs, txs);
+
+        // A node serving a blob which no pfb pays for is caught while fetching, instead of panicking once the
+        // blob is extracted and proven
+        let (block, _, eds) = builder()
+            .with_blob(TestBlob::rollup("celestia1bob", vec![2u8; 100]).unpaid())
+            .build_with_eds(&BTreeSet::from([TEST_NAMESPACE]));
+        let node = MockNode::start(block_responses(&block, &eds)).await;
+        assert!(matches!(
+            service(&node).fetch_block(height).await,
+            Err(DaServiceError::InvalidPfb { height: failed, .. }) if failed == height
+        ));
+    }
+
+    #[test]
+    fn test_verify_namespaced_shares() {
+        let (block, _) = single_blob_block(4, vec![3u8; 3000]);
+        let dah = &block.header.dah;
+        let response = namespaced_shares_response(&block);
+        let rows = response.as_array().unwrap().len();
+        assert!(rows > 1, "the blob should span several rows");
+        let verify = |response: &serde_json::Value| {
+            let response: RpcNamespacedSharesResponse =
+                serde_json::from_value(response.clone()).unwrap();
+            verify_namespaced_shares(TEST_NAMESPACE, dah, response)
+        };
+
+        let shares = verify(&response).expect("shares must be valid");
+        let expected: Vec<Share> = block
+            .rollup_namespace(TEST_NAMESPACE)
+            .unwrap()
+            .rows
+            .iter()
+            .flat_map(|row| row.shares.iter().cloned())
+            .filter(|share| share.namespace() == TEST_NAMESPACE)
+            .collect();
+        assert_eq!(shares, expected);
+
+        // A tampered share is rejected
+        let mut tampered = response.clone();
+        let share = &mut tampered[0]["Shares"][0];
+        let mut bytes = base64::decode(share.as_str().unwrap()).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        *share = base64::encode(bytes).into();
+        assert_eq!(verify(&tampered), Err("invalid namespace proof"));
+
+        // So is a withheld share, or a withheld row
+        let mut withheld = response.clone();
+        withheld[rows - 1]["Shares"].as_array_mut().unwrap().pop();
+        assert!(verify(&withheld).is_err());
+        let mut missing_row = response.clone();
+        missing_row.as_array_mut().unwrap().pop();
+        assert_eq!(
+            verify(&missing_row),
+            Err("missing rows containing the namespace")
+        );
+
+        // Emptying one of the rows which hold the namespace can't pass as a proof of absence
+        let mut emptied = response.clone();
+        emptied[rows - 1]["Shares"] = serde_json::json!([]);
+        assert_eq!(
+            verify(&emptied),
+            Err("empty row among several rows containing the namespace")
+        );
+    }
+
+    #[test]
+    fn test_emptied_single_row_is_checked_against_the_square() {
+        let (block, _) = single_blob_block(4, vec![3u8; 100]);
+        let mut response = namespaced_shares_response(&block);
+        assert_eq!(response.as_array().unwrap().len(), 1);
+        response[0]["Shares"] = serde_json::json!([]);
+        let response: RpcNamespacedSharesResponse = serde_json::from_value(response).unwrap();
+
+        // The response alone looks like a proof of absence...
+        let shares = verify_namespaced_shares(TEST_NAMESPACE, &block.header.dah, response).unwrap();
+        assert!(shares.is_empty());
+        // ...but the row of the square holds the namespace
+        let rows = &block.rollup_namespace(TEST_NAMESPACE).unwrap().rows;
+        assert!(matches!(
+            ensure_absent(5, TEST_NAMESPACE, rows),
+            Err(DaServiceError::InvalidSharesProof { height: 5, .. })
+        ));
+        assert!(ensure_absent(5, NamespaceId(*b"sov-none"), rows).is_ok());
+    }
+
+    fn fast_retry() -> RetryConfig {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_yields_blocks_in_order() {
+        // Within each window, later heights finish first
+        let blocks = prefetch(10, 4, fast_retry(), |height| async move {
+            tokio::time::sleep(Duration::from_millis(3 - height % 4)).await;
+            Ok::<u64, String>(height)
+        });
+        let heights: Vec<u64> = blocks.take(10).map(Result::unwrap).collect().await;
+        assert_eq!(heights, (10..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_window_is_bounded() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let highest_requested = Arc::new(AtomicU64::new(0));
+        let fetch = {
+            let (in_flight, max_in_flight, highest_requested) = (
+                in_flight.clone(),
+                max_in_flight.clone(),
+                highest_requested.clone(),
+            );
+            move |height: u64| {
+                let (in_flight, max_in_flight, highest_requested) = (
+                    in_flight.clone(),
+                    max_in_flight.clone(),
+                    highest_requested.clone(),
+                );
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(now, Ordering::SeqCst);
+                    highest_requested.fetch_max(height, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<u64, String>(height)
+                }
+            }
+        };
+
+        let heights: Vec<u64> = prefetch(0, 3, fast_retry(), fetch)
+            .take(5)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(heights, vec![0, 1, 2, 3, 4]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        // When block 4 is yielded, at most the two blocks after it have been requested
+        assert!(highest_requested.load(Ordering::SeqCst) <= 6);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_retries_failed_heights() {
+        let attempts = Arc::new(Mutex::new(BTreeMap::<u64, u32>::new()));
+        // Fails the first `failures` attempts to fetch height 2
+        let failing_at_2 = |failures: u32| {
+            let attempts = attempts.clone();
+            move |height: u64| {
+                let attempts = attempts.clone();
+                async move {
+                    let mut attempts = attempts.lock().unwrap();
+                    let count = attempts.entry(height).or_insert(0);
+                    *count += 1;
+                    if height == 2 && *count <= failures {
+                        Err(format!("height {} failed", height))
+                    } else {
+                        Ok(height)
+                    }
+                }
+            }
+        };
+
+        // A failed height is retried before any later block is yielded
+        let results: Vec<_> = prefetch(0, 3, fast_retry(), failing_at_2(2))
+            .take(5)
+            .collect()
+            .await;
+        assert_eq!(
+            results,
+            (0..5).map(Ok).collect::<Vec<Result<u64, String>>>()
+        );
+        assert_eq!(attempts.lock().unwrap()[&2], 3);
+
+        // Once the retries are exhausted, the error is yielded and the stream ends
+        attempts.lock().unwrap().clear();
+        let results: Vec<_> = prefetch(0, 3, fast_retry(), failing_at_2(u32::MAX))
+            .collect()
+            .await;
+        assert_eq!(
+            results,
+            vec![Ok(0), Ok(1), Err("height 2 failed".to_string())]
+        );
+        assert_eq!(attempts.lock().unwrap()[&2], 3);
+    }
 }
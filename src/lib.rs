@@ -4,10 +4,19 @@ pub mod celestia;
 pub mod shares;
 pub use celestia::*;
 
+pub mod cid;
+
+#[cfg(feature = "native")]
+pub mod block_cache;
 #[cfg(feature = "native")]
 pub mod da_service;
 pub mod pfb;
+#[cfg(feature = "native")]
+pub mod rpc;
 pub mod share_commit;
+mod telemetry;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod types;
 mod utils;
 pub mod verifier;
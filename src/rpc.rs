@@ -0,0 +1,854 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use jsonrpsee::{
+    core::{
+        client::{CertificateStore, ClientT, Subscription, SubscriptionClientT},
+        traits::ToRpcParams,
+        Error as JsonRpcError,
+    },
+    http_client::{HeaderMap, HttpClient, HttpClientBuilder},
+    ws_client::{WsClient, WsClientBuilder},
+};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{telemetry, utils::BoxError, ExtendedHeader};
+
+/// Controls how failed RPC calls are retried. Only idempotent reads are ever retried.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// The maximum number of times to retry a failed call (in addition to the first attempt)
+    pub max_retries: u32,
+    /// The delay before the first retry, in milliseconds. The delay doubles after each failed retry.
+    pub initial_backoff_ms: u64,
+    /// The maximum delay between two retries, in milliseconds
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns the delay before the given retry (counting from zero)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_backoff_ms
+            .saturating_mul(1u64.checked_shl(retry).unwrap_or(u64::MAX));
+        Duration::from_millis(std::cmp::min(delay, self.max_backoff_ms))
+    }
+}
+
+/// How requests are spread across several endpoints
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointSelection {
+    /// Prefer endpoints in the order in which they are configured, using later ones only while earlier ones
+    /// are unhealthy
+    Priority,
+    /// Rotate through the healthy endpoints
+    RoundRobin,
+}
+
+impl Default for EndpointSelection {
+    fn default() -> Self {
+        EndpointSelection::Priority
+    }
+}
+
+/// Controls how a client with several endpoints selects between them and detects unhealthy ones
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct FailoverConfig {
+    pub selection: EndpointSelection,
+    /// How long a failing or lagging endpoint is avoided for, in milliseconds
+    pub cooldown_ms: u64,
+    /// How often to compare the heads of all endpoints, in milliseconds
+    pub health_check_interval_ms: u64,
+    /// An endpoint whose head is more than this many blocks behind the highest head is considered lagging
+    pub max_head_lag: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            selection: EndpointSelection::default(),
+            cooldown_ms: 30_000,
+            health_check_interval_ms: 10_000,
+            max_head_lag: 3,
+        }
+    }
+}
+
+/// An error returned by a Celestia RPC call
+#[derive(Debug)]
+pub enum RpcError {
+    /// A transient failure, such as a timeout or a dropped connection. Retryable reads only return this
+    /// error once every retry has failed.
+    Retryable(JsonRpcError),
+    /// A failure which retrying can't fix, such as an error response from the node or a response which
+    /// couldn't be decoded
+    Permanent(JsonRpcError),
+    /// The response to `method` would be `size` bytes, which exceeds the configured `limit`
+    ResponseTooLarge {
+        method: String,
+        size: u64,
+        limit: u32,
+    },
+}
+
+impl RpcError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RpcError::Retryable(_))
+    }
+
+    pub(crate) fn classify(err: JsonRpcError) -> Self {
+        match err {
+            JsonRpcError::Transport(_)
+            | JsonRpcError::RequestTimeout
+            | JsonRpcError::RestartNeeded(_)
+            | JsonRpcError::MaxSlotsExceeded => RpcError::Retryable(err),
+            _ => RpcError::Permanent(err),
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Retryable(e) => write!(f, "RpcError::Retryable({})", e),
+            RpcError::Permanent(e) => write!(f, "RpcError::Permanent({})", e),
+            RpcError::ResponseTooLarge {
+                method,
+                size,
+                limit,
+            } => write!(
+                f,
+                "RpcError::ResponseTooLarge {{ method: {}, size: {}, limit: {} }}",
+                method, size, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// The connection used to reach the Celestia node
+#[derive(Debug, Clone)]
+pub enum RpcTransport {
+    Http(HttpClient),
+    Ws(WsConnection),
+}
+
+/// Connection settings shared by both transports
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+    /// Headers sent with every request (and with the websocket handshake)
+    pub headers: HeaderMap,
+    /// The maximum size of a request, in bytes
+    pub max_request_size: u32,
+    /// The maximum size of a response, in bytes
+    pub max_response_size: u32,
+    /// The maximum time the underlying client waits for a response. [`RpcClient`] applies its own timeout
+    /// on top of this one.
+    pub request_timeout: Duration,
+    /// The maximum time to wait for a websocket connection to be established
+    pub connection_timeout: Duration,
+    /// The root certificates trusted for `https://` and `wss://` addresses
+    pub certificate_store: CertificateStore,
+}
+
+impl RpcTransport {
+    /// Creates a transport for the given address, using websockets for `ws://` and `wss://` addresses and
+    /// http otherwise
+    pub fn from_address(address: &str, options: TransportOptions) -> Result<Self, BoxError> {
+        let scheme = address
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            Some("ws") | Some("wss") => Ok(RpcTransport::Ws(WsConnection::new(
+                address.to_string(),
+                options,
+            ))),
+            Some("http") | Some("https") => Ok(RpcTransport::Http(
+                HttpClientBuilder::default()
+                    .set_headers(options.headers)
+                    .max_request_size(options.max_request_size)
+                    .max_response_size(options.max_response_size)
+                    .request_timeout(options.request_timeout)
+                    .certificate_store(options.certificate_store)
+                    .build(address)?,
+            )),
+            _ => Err(anyhow::format_err!(
+                "unsupported scheme in rpc address {}",
+                address
+            )),
+        }
+    }
+}
+
+/// A websocket connection, which is established on first use and re-established if it drops. All calls
+/// made through the connection (and its clones) are multiplexed over a single socket.
+#[derive(Debug, Clone)]
+pub struct WsConnection {
+    url: String,
+    options: TransportOptions,
+    client: Arc<Mutex<Option<Arc<WsClient>>>>,
+}
+
+impl WsConnection {
+    pub fn new(url: String, options: TransportOptions) -> Self {
+        Self {
+            url,
+            options,
+            client: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn client(&self) -> Result<Arc<WsClient>, JsonRpcError> {
+        let mut client = self.client.lock().await;
+        match client.as_ref() {
+            Some(connected) if connected.is_connected() => Ok(connected.clone()),
+            _ => {
+                info!("Connecting to {}...", self.url);
+                let connected = Arc::new(
+                    WsClientBuilder::default()
+                        .set_headers(self.options.headers.clone())
+                        .max_request_size(self.options.max_request_size)
+                        .max_response_size(self.options.max_response_size)
+                        .request_timeout(self.options.request_timeout)
+                        .connection_timeout(self.options.connection_timeout)
+                        .certificate_store(self.options.certificate_store)
+                        .build(&self.url)
+                        .await?,
+                );
+                *client = Some(connected.clone());
+                Ok(connected)
+            }
+        }
+    }
+}
+
+/// A single node which the client can send requests to
+#[derive(Debug)]
+struct Endpoint {
+    transport: RpcTransport,
+    /// While set, the endpoint has recently failed a call, and other endpoints are preferred
+    failed_until: std::sync::Mutex<Option<Instant>>,
+    /// While set, the last health check found the endpoint lagging behind, and other endpoints are preferred.
+    /// Kept apart from `failed_until`, so that a passing health check doesn't end the cooldown of a failed call.
+    lagging_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn new(transport: RpcTransport) -> Self {
+        Self {
+            transport,
+            failed_until: std::sync::Mutex::new(None),
+            lagging_until: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        let now = Instant::now();
+        let expired = |until: &std::sync::Mutex<Option<Instant>>| {
+            until.lock().unwrap().map_or(true, |until| now >= until)
+        };
+        expired(&self.failed_until) && expired(&self.lagging_until)
+    }
+
+    fn set_failed(&self, cooldown: Duration) {
+        *self.failed_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+
+    fn set_lagging(&self, lagging: bool, cooldown: Duration) {
+        *self.lagging_until.lock().unwrap() = lagging.then(|| Instant::now() + cooldown);
+    }
+}
+
+/// A client for the Celestia node's RPC interface, which applies a timeout to every call and retries
+/// idempotent reads with exponential backoff. If several endpoints are configured, reads fail over to
+/// the next healthy endpoint before backing off.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    endpoints: Arc<Vec<Endpoint>>,
+    request_timeout: Duration,
+    retry: RetryConfig,
+    failover: FailoverConfig,
+    /// The endpoint which round-robin selection starts from next
+    next_endpoint: Arc<AtomicUsize>,
+    /// When the heads of the endpoints were last compared
+    last_health_check: Arc<std::sync::Mutex<Option<Instant>>>,
+    /// The response size limit of the transport, if known
+    max_response_size: Option<u32>,
+}
+
+impl RpcClient {
+    pub fn new(transport: RpcTransport, request_timeout: Duration, retry: RetryConfig) -> Self {
+        Self::with_failover(
+            vec![transport],
+            request_timeout,
+            retry,
+            FailoverConfig::default(),
+        )
+        .expect("a single endpoint is always valid")
+    }
+
+    /// Creates a client which spreads requests across several endpoints
+    pub fn with_failover(
+        transports: Vec<RpcTransport>,
+        request_timeout: Duration,
+        retry: RetryConfig,
+        failover: FailoverConfig,
+    ) -> Result<Self, BoxError> {
+        anyhow::ensure!(
+            !transports.is_empty(),
+            "at least one rpc endpoint is required"
+        );
+        Ok(Self {
+            endpoints: Arc::new(transports.into_iter().map(Endpoint::new).collect()),
+            request_timeout,
+            retry,
+            failover,
+            next_endpoint: Arc::new(AtomicUsize::new(0)),
+            last_health_check: Arc::new(std::sync::Mutex::new(None)),
+            max_response_size: None,
+        })
+    }
+
+    /// Records the response size limit of the transport, so that oversized responses can be rejected
+    /// before they are requested
+    pub fn with_max_response_size(mut self, limit: u32) -> Self {
+        self.max_response_size = Some(limit);
+        self
+    }
+
+    /// Returns the number of configured endpoints
+    pub fn endpoint_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// Returns a [`RpcError::ResponseTooLarge`] error if a response of `size` bytes to `method` would exceed
+    /// the response size limit
+    pub fn check_response_size(&self, method: &str, size: u64) -> Result<(), RpcError> {
+        match self.max_response_size {
+            Some(limit) if size > limit as u64 => Err(RpcError::ResponseTooLarge {
+                method: method.to_string(),
+                size,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Performs an idempotent read, failing over between endpoints and retrying transient failures
+    pub async fn request<R, P>(&self, method: &str, params: P) -> Result<R, RpcError>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Clone + Send,
+    {
+        self.request_with_endpoint(method, params)
+            .await
+            .map(|(_, response)| response)
+    }
+
+    /// Performs an idempotent read against two different endpoints, so that their responses can be
+    /// cross-checked. The second response is `None` if only one endpoint is configured.
+    pub async fn request_pair<R, P>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<(R, Option<R>), RpcError>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Clone + Send,
+    {
+        let (first_idx, first) = self.request_with_endpoint(method, params.clone()).await?;
+        let mut last_err = None;
+        for idx in self.endpoint_order() {
+            if idx == first_idx {
+                continue;
+            }
+            match self.call_endpoint(idx, method, params.clone()).await {
+                Ok(second) => return Ok((first, Some(second))),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok((first, None)),
+        }
+    }
+
+    async fn request_with_endpoint<R, P>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<(usize, R), RpcError>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Clone + Send,
+    {
+        let mut retry = 0;
+        loop {
+            self.check_health().await;
+            let mut last_err = None;
+            for idx in self.endpoint_order() {
+                match self.call_endpoint(idx, method, params.clone()).await {
+                    Ok(response) => return Ok((idx, response)),
+                    Err(err) if err.is_retryable() => {
+                        if self.endpoints.len() > 1 {
+                            warn!(
+                                "RPC call {} to endpoint {} failed with {}. Failing over",
+                                method, idx, err
+                            );
+                        }
+                        last_err = Some(err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let err = last_err.expect("there is at least one endpoint");
+            if retry >= self.retry.max_retries {
+                return Err(err);
+            }
+            let backoff = self.retry.backoff(retry);
+            warn!(
+                "RPC call {} failed with {}. Retrying in {:?}",
+                method, err, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            retry += 1;
+        }
+    }
+
+    /// Performs a single call to the preferred endpoint, without retrying or failing over. Used for calls
+    /// which are not idempotent.
+    pub async fn request_once<R, P>(&self, method: &str, params: P) -> Result<R, RpcError>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Send,
+    {
+        let idx = self.endpoint_order()[0];
+        self.call_endpoint(idx, method, params).await
+    }
+
+    async fn call_endpoint<R, P>(&self, idx: usize, method: &str, params: P) -> Result<R, RpcError>
+    where
+        R: DeserializeOwned,
+        P: ToRpcParams + Send,
+    {
+        let call = async {
+            match &self.endpoints[idx].transport {
+                RpcTransport::Http(client) => client.request(method, params).await,
+                RpcTransport::Ws(connection) => {
+                    connection.client().await?.request(method, params).await
+                }
+            }
+        };
+        let started = Instant::now();
+        let result = match tokio::time::timeout(self.request_timeout, call).await {
+            Ok(result) => result.map_err(RpcError::classify),
+            Err(_) => Err(RpcError::Retryable(JsonRpcError::RequestTimeout)),
+        };
+        telemetry::record_rpc_call(method, started.elapsed(), result.is_ok());
+        if matches!(&result, Err(err) if err.is_retryable()) {
+            self.endpoints[idx].set_failed(self.cooldown());
+        }
+        result
+    }
+
+    /// Returns the indices of the endpoints in the order in which they should be tried. Healthy endpoints
+    /// always come first.
+    fn endpoint_order(&self) -> Vec<usize> {
+        let count = self.endpoints.len();
+        let start = match self.failover.selection {
+            EndpointSelection::Priority => 0,
+            EndpointSelection::RoundRobin => self.next_endpoint.fetch_add(1, Ordering::Relaxed),
+        };
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) = (0..count)
+            .map(|offset| (start + offset) % count)
+            .partition(|&idx| self.endpoints[idx].is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_millis(self.failover.cooldown_ms)
+    }
+
+    /// Compares the heads of all endpoints if the last comparison is stale, and marks endpoints which are
+    /// erroring or lagging behind the highest head as unhealthy
+    async fn check_health(&self) {
+        if self.endpoints.len() < 2 {
+            return;
+        }
+        // Claim the check before querying the endpoints, so that the lock isn't held while waiting on them
+        // and concurrent requests don't start checks of their own
+        {
+            let mut last_health_check = self.last_health_check.lock().unwrap();
+            let interval = Duration::from_millis(self.failover.health_check_interval_ms);
+            if matches!(*last_health_check, Some(checked) if checked.elapsed() < interval) {
+                return;
+            }
+            *last_health_check = Some(Instant::now());
+        }
+
+        let heads = futures::future::join_all((0..self.endpoints.len()).map(|idx| async move {
+            self.call_endpoint::<ExtendedHeader, _>(idx, "header.LocalHead", Vec::<u64>::new())
+                .await
+                .map(|head| head.height())
+        }))
+        .await;
+        let highest = heads.iter().filter_map(|head| head.as_ref().ok()).max();
+        for (idx, head) in heads.iter().enumerate() {
+            // A failed check has already put the endpoint into cooldown, like any other failed call
+            let lagging = match (head, highest) {
+                (Ok(head), Some(highest)) if highest - head > self.failover.max_head_lag => {
+                    warn!(
+                        "Endpoint {} is lagging at height {} (highest known head is {})",
+                        idx, head, highest
+                    );
+                    true
+                }
+                (Ok(_), _) => false,
+                (Err(err), _) => {
+                    warn!("Health check of endpoint {} failed with {}", idx, err);
+                    continue;
+                }
+            };
+            self.endpoints[idx].set_lagging(lagging, self.cooldown());
+        }
+    }
+
+    /// Returns true if any endpoint supports subscriptions
+    pub fn supports_subscriptions(&self) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| matches!(endpoint.transport, RpcTransport::Ws(_)))
+    }
+
+    /// Subscribes to notifications from the preferred websocket endpoint
+    pub async fn subscribe<N, P>(
+        &self,
+        subscribe_method: &str,
+        params: P,
+        unsubscribe_method: &str,
+    ) -> Result<Subscription<N>, RpcError>
+    where
+        N: DeserializeOwned,
+        P: ToRpcParams + Send,
+    {
+        let connection = self
+            .endpoint_order()
+            .into_iter()
+            .find_map(|idx| match &self.endpoints[idx].transport {
+                RpcTransport::Ws(connection) => Some(connection),
+                RpcTransport::Http(_) => None,
+            })
+            .ok_or_else(|| {
+                RpcError::Permanent(JsonRpcError::Custom(
+                    "subscriptions require a websocket transport".to_string(),
+                ))
+            })?;
+        let client = connection.client().await.map_err(RpcError::classify)?;
+        client
+            .subscribe(subscribe_method, params, unsubscribe_method)
+            .await
+            .map_err(RpcError::classify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use jsonrpsee::{
+        core::{client::CertificateStore, Error as JsonRpcError},
+        http_client::HeaderMap,
+    };
+
+    use super::{
+        EndpointSelection, FailoverConfig, RetryConfig, RpcClient, RpcError, RpcTransport,
+        TransportOptions,
+    };
+    use crate::{
+        test_utils::{forked_header_responses, header_json, header_responses, MockNode},
+        ExtendedHeader,
+    };
+
+    fn options() -> TransportOptions {
+        TransportOptions {
+            headers: HeaderMap::new(),
+            max_request_size: 1024,
+            max_response_size: 1024,
+            request_timeout: Duration::from_secs(1),
+            connection_timeout: Duration::from_secs(1),
+            certificate_store: CertificateStore::WebPki,
+        }
+    }
+
+    /// A client for the given nodes which never retries, so that every failover is visible in the calls
+    /// received by the nodes
+    fn mock_client(nodes: &[&MockNode], failover: FailoverConfig) -> RpcClient {
+        let options = TransportOptions {
+            max_response_size: 1024 * 1024,
+            ..options()
+        };
+        let transports = nodes
+            .iter()
+            .map(|node| RpcTransport::from_address(&node.url, options.clone()).unwrap())
+            .collect();
+        let retry = RetryConfig {
+            max_retries: 0,
+            ..Default::default()
+        };
+        RpcClient::with_failover(transports, Duration::from_secs(5), retry, failover).unwrap()
+    }
+
+    /// Starts a node which passes health checks at height 10, but fails every other call
+    async fn failing_node() -> MockNode {
+        MockNode::start(|method, _| (method == "header.LocalHead").then(|| header_json(10))).await
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_limit() {
+        let config = RetryConfig {
+            max_retries: 100,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        };
+        assert_eq!(config.backoff(0), Duration::from_millis(100));
+        assert_eq!(config.backoff(1), Duration::from_millis(200));
+        assert_eq!(config.backoff(3), Duration::from_millis(800));
+        assert_eq!(config.backoff(4), Duration::from_millis(1000));
+        assert_eq!(config.backoff(99), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert!(RpcError::classify(JsonRpcError::RequestTimeout).is_retryable());
+        assert!(
+            RpcError::classify(JsonRpcError::Transport(anyhow::anyhow!("reset"))).is_retryable()
+        );
+        assert!(!RpcError::classify(JsonRpcError::Custom("bad request".into())).is_retryable());
+    }
+
+    #[test]
+    fn test_transport_is_selected_by_scheme() {
+        let transport = |address: &str| RpcTransport::from_address(address, options());
+        assert!(matches!(
+            transport("http://localhost:26658"),
+            Ok(RpcTransport::Http(_))
+        ));
+        assert!(matches!(
+            transport("WSS://localhost:26658"),
+            Ok(RpcTransport::Ws(_))
+        ));
+        assert!(transport("localhost:26658").is_err());
+    }
+
+    #[test]
+    fn test_response_size_check() {
+        let client = RpcClient::new(
+            RpcTransport::from_address("http://localhost:26658", options()).unwrap(),
+            Duration::from_secs(1),
+            RetryConfig::default(),
+        );
+        assert!(client.check_response_size("share.GetEDS", u64::MAX).is_ok());
+
+        let client = client.with_max_response_size(1024);
+        assert!(client.check_response_size("share.GetEDS", 1024).is_ok());
+        let err = client
+            .check_response_size("share.GetEDS", 2048)
+            .unwrap_err();
+        assert!(!err.is_retryable());
+        assert_eq!(
+            err.to_string(),
+            "RpcError::ResponseTooLarge { method: share.GetEDS, size: 2048, limit: 1024 }"
+        );
+    }
+
+    #[test]
+    fn test_endpoint_order() {
+        let client = |selection| {
+            let transports = (0..3)
+                .map(|_| RpcTransport::from_address("http://localhost:26658", options()).unwrap())
+                .collect();
+            RpcClient::with_failover(
+                transports,
+                Duration::from_secs(1),
+                RetryConfig::default(),
+                FailoverConfig {
+                    selection,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let priority = client(EndpointSelection::Priority);
+        assert_eq!(priority.endpoint_order(), vec![0, 1, 2]);
+        assert_eq!(priority.endpoint_order(), vec![0, 1, 2]);
+        // Unhealthy endpoints are only tried after all of the healthy ones
+        priority.endpoints[0].set_failed(Duration::from_secs(60));
+        assert_eq!(priority.endpoint_order(), vec![1, 2, 0]);
+        priority.endpoints[0].set_failed(Duration::ZERO);
+        assert_eq!(priority.endpoint_order(), vec![0, 1, 2]);
+
+        let round_robin = client(EndpointSelection::RoundRobin);
+        assert_eq!(round_robin.endpoint_order(), vec![0, 1, 2]);
+        assert_eq!(round_robin.endpoint_order(), vec![1, 2, 0]);
+        round_robin.endpoints[0].set_failed(Duration::from_secs(60));
+        assert_eq!(round_robin.endpoint_order(), vec![2, 1, 0]);
+
+        assert!(RpcClient::with_failover(
+            vec![],
+            Duration::from_secs(1),
+            RetryConfig::default(),
+            FailoverConfig::default()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_the_next_endpoint() {
+        let failing = failing_node().await;
+        let healthy = MockNode::start(header_responses(10)).await;
+        let client = mock_client(&[&failing, &healthy], FailoverConfig::default());
+
+        let header: ExtendedHeader = client
+            .request("header.GetByHeight", vec![5u64])
+            .await
+            .unwrap();
+        assert_eq!(header.height(), 5);
+        assert_eq!(failing.calls("header.GetByHeight"), 1);
+        assert_eq!(healthy.calls("header.GetByHeight"), 1);
+
+        // Once every endpoint has failed, the last error is returned
+        let failing_only = mock_client(&[&failing], FailoverConfig::default());
+        let err = failing_only
+            .request::<ExtendedHeader, _>("header.GetByHeight", vec![5u64])
+            .await
+            .unwrap_err();
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_failed_endpoint_cools_down() {
+        let failing = failing_node().await;
+        let healthy = MockNode::start(header_responses(10)).await;
+        let client = mock_client(
+            &[&failing, &healthy],
+            FailoverConfig {
+                cooldown_ms: 200,
+                health_check_interval_ms: 60_000,
+                ..Default::default()
+            },
+        );
+        let get_header = || client.request::<ExtendedHeader, _>("header.GetByHeight", vec![5u64]);
+
+        get_header().await.unwrap();
+        // While it cools down, the failed endpoint isn't tried even though it has priority
+        get_header().await.unwrap();
+        assert_eq!(failing.calls("header.GetByHeight"), 1);
+        assert_eq!(healthy.calls("header.GetByHeight"), 2);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        get_header().await.unwrap();
+        assert_eq!(failing.calls("header.GetByHeight"), 2);
+        assert_eq!(healthy.calls("header.GetByHeight"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_keeps_the_cooldown_of_a_failed_call() {
+        let failing = failing_node().await;
+        let healthy = MockNode::start(header_responses(10)).await;
+        let client = mock_client(
+            &[&failing, &healthy],
+            FailoverConfig {
+                cooldown_ms: 60_000,
+                health_check_interval_ms: 0,
+                ..Default::default()
+            },
+        );
+        let get_header = || client.request::<ExtendedHeader, _>("header.GetByHeight", vec![5u64]);
+
+        get_header().await.unwrap();
+        assert_eq!(failing.calls("header.GetByHeight"), 1);
+        // The failing endpoint passes the health check run before the next request, but stays in cooldown
+        get_header().await.unwrap();
+        assert_eq!(failing.calls("header.LocalHead"), 2);
+        assert_eq!(failing.calls("header.GetByHeight"), 1);
+        assert_eq!(healthy.calls("header.GetByHeight"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_endpoint_is_avoided() {
+        let lagging = MockNode::start(header_responses(100)).await;
+        let close = MockNode::start(header_responses(108)).await;
+        let synced = MockNode::start(header_responses(110)).await;
+        let client = mock_client(
+            &[&lagging, &close, &synced],
+            FailoverConfig {
+                max_head_lag: 3,
+                ..Default::default()
+            },
+        );
+
+        client
+            .request::<ExtendedHeader, _>("header.GetByHeight", vec![5u64])
+            .await
+            .unwrap();
+        for node in [&lagging, &close, &synced] {
+            assert_eq!(node.calls("header.LocalHead"), 1);
+        }
+        // An endpoint within `max_head_lag` of the highest head is still preferred over later ones
+        assert_eq!(lagging.calls("header.GetByHeight"), 0);
+        assert_eq!(close.calls("header.GetByHeight"), 1);
+        assert_eq!(synced.calls("header.GetByHeight"), 0);
+        assert_eq!(client.endpoint_order(), vec![1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_request_pair_reports_both_responses() {
+        let honest = MockNode::start(header_responses(10)).await;
+        let forked = MockNode::start(forked_header_responses(10)).await;
+        let client = mock_client(&[&honest, &forked], FailoverConfig::default());
+
+        let (first, second) = client
+            .request_pair::<ExtendedHeader, _>("header.GetByHeight", vec![5u64])
+            .await
+            .unwrap();
+        let second = second.expect("a second endpoint is configured");
+        assert_eq!(first.header.chain_id.as_str(), "mocha");
+        assert_eq!(second.header.chain_id.as_str(), "forked");
+        assert_ne!(first.header.hash(), second.header.hash());
+        assert_eq!(honest.calls("header.GetByHeight"), 1);
+        assert_eq!(forked.calls("header.GetByHeight"), 1);
+
+        // With a single endpoint, there is nothing to compare against
+        let single = mock_client(&[&honest], FailoverConfig::default());
+        let (_, second) = single
+            .request_pair::<ExtendedHeader, _>("header.GetByHeight", vec![5u64])
+            .await
+            .unwrap();
+        assert!(second.is_none());
+    }
+}
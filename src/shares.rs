@@ -4,7 +4,7 @@ use base64::STANDARD;
 use borsh::{BorshDeserialize, BorshSerialize};
 use nmt_rs::{NamespaceId, NAMESPACE_ID_LEN};
 use prost::{
-    bytes::{Buf, BytesMut},
+    bytes::{Buf, BufMut, BytesMut},
     encoding::decode_varint,
     DecodeError,
 };
@@ -28,6 +28,9 @@ pub const SEQUENCE_LENGTH_BYTES: usize = 4;
 pub fn skip_varint(mut bytes: impl Buf) -> Result<usize, ErrInvalidVarint> {
     // A varint may contain up to 10 bytes
     for i in 0..10 {
+        if !bytes.has_remaining() {
+            return Err(ErrInvalidVarint);
+        }
         // If the continuation bit is not set, we're done
         if bytes.get_u8() < 0x80 {
             return Ok(i + 1);
@@ -49,7 +52,7 @@ pub struct ErrInvalidVarint;
 /// The size of a share, in bytes
 const SHARE_SIZE: usize = 512;
 /// The size of base64 encoded share, in bytes
-const B64_SHARE_SIZE: usize = 684;
+pub(crate) const B64_SHARE_SIZE: usize = 684;
 
 #[derive(
     Debug, Clone, PartialEq, serde::Serialize, Deserialize, BorshDeserialize, BorshSerialize,
@@ -82,11 +85,10 @@ impl<'de> Deserialize<'de> for Share {
     {
         let mut share = <sovereign_sdk::Bytes as Deserialize>::deserialize(deserializer)?;
         if share.len() == B64_SHARE_SIZE {
-            let mut decoded = BytesMut::with_capacity(SHARE_SIZE);
-            unsafe { decoded.set_len(SHARE_SIZE) }
-            base64::decode_config_slice(share, STANDARD, &mut decoded[..])
-                .map_err(|_| Error::custom("Invalid base64 encoding"))?;
-            share = decoded.freeze()
+            // Unpadded input of this length decodes to more than a share, so the length is checked below
+            share = base64::decode_config(share, STANDARD)
+                .map_err(|_| Error::custom("Invalid base64 encoding"))?
+                .into();
         }
         if share.len() != SHARE_SIZE {
             // let expected = Unexpected::Bytes(&share);
@@ -104,16 +106,35 @@ fn is_continuation_unchecked(share: &[u8]) -> bool {
 }
 
 fn enforce_version_zero(share: &[u8]) {
-    assert!(share[8] & !0x01 == 0)
+    assert!(is_version_zero(share))
+}
+
+fn is_version_zero(share: &[u8]) -> bool {
+    share[8] & !0x01 == 0
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum ShareError {
     NotAStartShare,
     InvalidEncoding,
+    /// The sequence length of the start share exceeds the data held by the blob's shares
+    SequenceTooLong,
+}
+
+impl Display for ShareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShareError::NotAStartShare => f.write_str("ShareError::NotAStartShare"),
+            ShareError::InvalidEncoding => f.write_str("ShareError::InvalidEncoding"),
+            ShareError::SequenceTooLong => f.write_str("ShareError::SequenceTooLong"),
+        }
+    }
 }
 
+impl std::error::Error for ShareError {}
+
 impl Share {
+    /// Panics if the share has a version other than zero. Use [`Share::try_new`] for untrusted input.
     pub fn new(inner: Bytes) -> Self {
         enforce_version_zero(inner.as_ref());
         if is_continuation_unchecked(inner.as_ref()) {
@@ -123,6 +144,17 @@ impl Share {
         }
     }
 
+    /// Checks that `inner` is a complete share of version zero
+    pub fn try_new(inner: Bytes) -> Result<Self, ShareParsingError> {
+        if inner.len() != SHARE_SIZE {
+            return Err(ShareParsingError::ErrWrongLength);
+        }
+        if !is_version_zero(inner.as_ref()) {
+            return Err(ShareParsingError::ErrUnsupportedVersion);
+        }
+        Ok(Self::new(inner))
+    }
+
     pub fn as_serialized(&self) -> &[u8] {
         self.raw_inner_ref()
     }
@@ -179,7 +211,8 @@ impl Share {
                 if idx_of_next_start == 0 {
                     None
                 } else {
-                    Some(idx_of_next_start - self.get_data_offset())
+                    // A malformed share may point into its own metadata
+                    idx_of_next_start.checked_sub(self.get_data_offset())
                 }
             }
             // Start shares always have a sequence beginning at the first byte
@@ -232,11 +265,13 @@ impl Share {
                 if next_legal_start_offset == idx {
                     return true;
                 }
-                if let Ok((tx_len, len_of_len)) = read_varint(&mut remaining_data) {
-                    next_legal_start_offset += tx_len as usize + len_of_len;
-                    remaining_data.advance(tx_len as usize);
-                } else {
-                    return false;
+                match read_varint(&mut remaining_data) {
+                    // A tx which claims to run past the end of the share can't be followed by another one
+                    Ok((tx_len, len_of_len)) if tx_len as usize <= remaining_data.remaining() => {
+                        next_legal_start_offset += tx_len as usize + len_of_len;
+                        remaining_data.advance(tx_len as usize);
+                    }
+                    _ => return false,
                 }
             }
         }
@@ -249,6 +284,7 @@ impl Share {
 pub enum ShareParsingError {
     ErrInvalidBase64,
     ErrWrongLength,
+    ErrUnsupportedVersion,
 }
 
 impl Display for ShareParsingError {
@@ -258,6 +294,9 @@ impl Display for ShareParsingError {
                 f.write_str("ShareParsingError::ErrInvalidBase64")
             }
             ShareParsingError::ErrWrongLength => f.write_str("ShareParsingError::ErrWrongLength"),
+            ShareParsingError::ErrUnsupportedVersion => {
+                f.write_str("ShareParsingError::ErrUnsupportedVersion")
+            }
         }
     }
 }
@@ -279,18 +318,11 @@ impl NamespaceGroup {
             );
             return Err(ShareParsingError::ErrWrongLength);
         }
-        let mut shares = Vec::with_capacity((output.len() / 512) + 1);
-        while output.len() > SHARE_SIZE {
-            shares.push(Share::new(output.split_to(SHARE_SIZE)));
-        }
-        shares.push(Share::new(output));
-        // Check whether these shares come from a reserved (compact) namespace
-
-        if shares[0].namespace().is_reserved() {
-            Ok(Self::Compact(shares))
-        } else {
-            Ok(Self::Sparse(shares))
+        let mut shares = Vec::with_capacity(output.len() / SHARE_SIZE);
+        while output.has_remaining() {
+            shares.push(Share::try_new(output.split_to(SHARE_SIZE))?);
         }
+        Ok(Self::from_parsed_shares(shares))
     }
 
     pub fn from_b64_shares(encoded_shares: &Vec<String>) -> Result<Self, ShareParsingError> {
@@ -301,17 +333,26 @@ impl NamespaceGroup {
         for share in encoded_shares {
             let decoded_vec =
                 base64::decode(share).map_err(|_| ShareParsingError::ErrInvalidBase64)?;
-            if decoded_vec.len() != 512 {
-                return Err(ShareParsingError::ErrWrongLength);
-            }
-            let share = Share::new(decoded_vec.into());
-            shares.push(share)
+            shares.push(Share::try_new(decoded_vec.into())?)
         }
+        Ok(Self::from_parsed_shares(shares))
+    }
 
-        if shares[0].namespace().is_reserved() {
-            Ok(Self::Compact(shares))
-        } else {
-            Ok(Self::Sparse(shares))
+    /// Builds a group from untrusted raw shares, checking each of them
+    pub fn from_shares(shares: Vec<Vec<u8>>) -> Result<Self, ShareParsingError> {
+        let shares = shares
+            .into_iter()
+            .map(|share| Share::try_new(Bytes::from(share)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self::from_parsed_shares(shares))
+    }
+
+    /// Groups shares as compact if they come from a reserved namespace, and as sparse otherwise. An empty
+    /// group is sparse.
+    fn from_parsed_shares(shares: Vec<Share>) -> Self {
+        match shares.first() {
+            Some(share) if share.namespace().is_reserved() => Self::Compact(shares),
+            _ => Self::Sparse(shares),
         }
     }
 
@@ -346,6 +387,14 @@ impl NamespaceGroup {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 pub struct Blob(pub Vec<Share>);
 
+impl Blob {
+    /// Returns the data of the blob as a single contiguous buffer. Blobs which fit in a single share
+    /// are returned without copying.
+    pub fn to_bytes(&self) -> Bytes {
+        BlobRef::with(&self.0).to_bytes()
+    }
+}
+
 impl<'a> From<BlobRef<'a>> for Blob {
     fn from(value: BlobRef<'a>) -> Self {
         Self(value.0.iter().map(|s| s.clone()).collect())
@@ -357,12 +406,12 @@ impl IntoIterator for Blob {
 
     type IntoIter = BlobIterator;
 
+    /// Panics if the blob doesn't begin with a start share, or if its shares hold less data than its sequence
+    /// length
     fn into_iter(self) -> Self::IntoIter {
-        let sequence_length = self.0[0]
-            .sequence_length()
-            .expect("blob must contain start share at idx 0");
+        let sequence_len = readable_len(&self.0).expect("blob must contain start share at idx 0");
         BlobIterator {
-            sequence_len: sequence_length as usize,
+            sequence_len,
             consumed: 0,
             current: self.0[0].data(),
             current_idx: 0,
@@ -379,20 +428,81 @@ impl<'a> BlobRef<'a> {
         Self(shares)
     }
 
-    pub fn data(&self) -> BlobRefIterator {
-        let sequence_length = self.0[0]
-            .sequence_length()
-            .expect("blob must contain start share at idx 0");
-        BlobRefIterator {
-            sequence_len: sequence_length as usize,
+    /// Returns an iterator over the data of the blob, which borrows from the underlying shares. Panics if the
+    /// blob doesn't begin with a start share, or if its shares hold less data than its sequence length.
+    pub fn data(&self) -> BlobRefIterator<'a> {
+        self.try_data()
+            .expect("blob must contain start share at idx 0")
+    }
+
+    /// Returns an iterator over the data of the blob, or an error if the blob doesn't begin with a start share
+    /// or if its shares hold less data than its sequence length
+    pub fn try_data(&self) -> Result<BlobRefIterator<'a>, ShareError> {
+        Ok(BlobRefIterator {
+            sequence_len: readable_len(self.0)?,
             consumed: 0,
-            current: self.0[0].data(),
+            current: self.0[0].data_ref(),
             current_idx: 0,
             shares: self.0,
+        })
+    }
+
+    /// Returns the data of the blob as a single contiguous buffer. Blobs which fit in a single share
+    /// are returned without copying.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut data = self.data();
+        if let [share] = self.0 {
+            let len = std::cmp::min(data.remaining(), data.current.len());
+            return share.data().slice(..len);
+        }
+        let mut output = BytesMut::with_capacity(data.remaining());
+        while data.has_remaining() {
+            let chunk = data.chunk();
+            // Never loop forever, even if the shares hold less data than expected
+            if chunk.is_empty() {
+                break;
+            }
+            output.extend_from_slice(chunk);
+            let len = chunk.len();
+            data.advance(len);
+        }
+        output.freeze()
+    }
+}
+
+/// Returns the number of bytes which can be read from a blob: the sequence length of its first share. Fails if
+/// the shares of the blob don't hold that much data.
+fn readable_len(shares: &[Share]) -> Result<usize, ShareError> {
+    let sequence_len = shares
+        .first()
+        .ok_or(ShareError::NotAStartShare)?
+        .sequence_length()?;
+    let available: usize = shares.iter().map(|share| share.data_ref().len()).sum();
+    match usize::try_from(sequence_len) {
+        Ok(sequence_len) if sequence_len <= available => Ok(sequence_len),
+        _ => Err(ShareError::SequenceTooLong),
+    }
+}
+
+/// Compares the contents of two buffers chunk by chunk, without copying either of them
+pub fn bufs_eq(mut left: impl Buf, mut right: impl Buf) -> bool {
+    if left.remaining() != right.remaining() {
+        return false;
+    }
+    while left.has_remaining() {
+        let (left_chunk, right_chunk) = (left.chunk(), right.chunk());
+        let len = std::cmp::min(left_chunk.len(), right_chunk.len());
+        if len == 0 || left_chunk[..len] != right_chunk[..len] {
+            return false;
         }
+        left.advance(len);
+        right.advance(len);
     }
+    true
 }
 
+/// An owning iterator over the data of a [`Blob`]. Shares are reference counted, so neither creating
+/// the iterator nor reading from it copies the blob's data.
 #[derive(Debug, Clone)]
 pub struct BlobIterator {
     sequence_len: usize,
@@ -421,15 +531,17 @@ impl Iterator for BlobIterator {
 
 impl std::io::Read for BlobIterator {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len(), self.remaining());
         let mut written = 0;
-        for byte in buf.iter_mut() {
-            *byte = match self.next() {
-                Some(byte) => {
-                    written += 1;
-                    byte
-                }
-                None => return Ok(written),
-            };
+        while written < len {
+            let chunk = self.chunk();
+            if chunk.is_empty() {
+                break;
+            }
+            let n = std::cmp::min(chunk.len(), len - written);
+            buf[written..written + n].copy_from_slice(&chunk[..n]);
+            self.advance(n);
+            written += n;
         }
         Ok(written)
     }
@@ -460,25 +572,36 @@ impl Buf for BlobIterator {
         chunk
     }
 
-    fn advance(&mut self, cnt: usize) {
+    fn advance(&mut self, mut cnt: usize) {
         self.consumed += cnt;
-        if self.current.remaining() > cnt {
-            self.current.advance(cnt);
-            return;
+        // Move on to the next share once the current one is exhausted, unless this is the last share
+        while self.current.remaining() <= cnt && self.current_idx + 1 < self.blob.0.len() {
+            cnt -= self.current.remaining();
+            self.current_idx += 1;
+            self.current = self.blob.0[self.current_idx].data();
         }
+        self.current.advance(cnt);
+    }
 
-        let next_cnt = cnt - self.current.remaining();
-        self.current_idx += 1;
-        self.current = self.blob.0[self.current_idx].data();
-        self.current.advance(next_cnt);
+    fn copy_to_bytes(&mut self, len: usize) -> Bytes {
+        assert!(len <= self.remaining(), "`len` greater than remaining");
+        // Fast path: if the requested data lies within the current share, share its buffer instead of copying
+        if len <= self.current.remaining() {
+            self.consumed += len;
+            return self.current.split_to(len);
+        }
+        let mut output = BytesMut::with_capacity(len);
+        output.put(Buf::take(&mut *self, len));
+        output.freeze()
     }
 }
 
+/// An iterator over the data of a [`BlobRef`], which borrows from the underlying shares
 #[derive(Debug, Clone)]
 pub struct BlobRefIterator<'a> {
     sequence_len: usize,
     consumed: usize,
-    current: Bytes,
+    current: &'a [u8],
     current_idx: usize,
     shares: &'a [Share],
 }
@@ -487,7 +610,7 @@ impl<'a> BlobRefIterator<'a> {
     pub fn current_position(&self) -> (usize, usize) {
         (
             self.current_idx,
-            self.shares[self.current_idx].data_ref().len() - self.current.remaining(),
+            self.shares[self.current_idx].data_ref().len() - self.current.len(),
         )
     }
 }
@@ -499,12 +622,13 @@ impl<'a> Iterator for BlobRefIterator<'a> {
         if self.consumed == self.sequence_len {
             return None;
         }
-        if self.current.has_remaining() {
+        if let Some((byte, rest)) = self.current.split_first() {
             self.consumed += 1;
-            return Some(self.current.get_u8());
+            self.current = rest;
+            return Some(*byte);
         }
         self.current_idx += 1;
-        self.current = self.shares[self.current_idx].data();
+        self.current = self.shares[self.current_idx].data_ref();
         self.next()
     }
 }
@@ -515,8 +639,8 @@ impl<'a> Buf for BlobRefIterator<'a> {
     }
 
     fn chunk(&self) -> &[u8] {
-        let chunk = if self.current.has_remaining() {
-            self.current.as_ref()
+        let chunk = if !self.current.is_empty() {
+            self.current
         } else {
             // If the current share is exhasted, try to take the data from the next one
             // if there is no next chunk, we're done. Return the empty slice.
@@ -534,17 +658,15 @@ impl<'a> Buf for BlobRefIterator<'a> {
         chunk
     }
 
-    fn advance(&mut self, cnt: usize) {
+    fn advance(&mut self, mut cnt: usize) {
         self.consumed += cnt;
-        if self.current.remaining() > cnt {
-            self.current.advance(cnt);
-            return;
+        // Move on to the next share once the current one is exhausted, unless this is the last share
+        while self.current.len() <= cnt && self.current_idx + 1 < self.shares.len() {
+            cnt -= self.current.len();
+            self.current_idx += 1;
+            self.current = self.shares[self.current_idx].data_ref();
         }
-
-        let next_cnt = cnt - self.current.remaining();
-        self.current_idx += 1;
-        self.current = self.shares[self.current_idx].data();
-        self.current.advance(next_cnt);
+        self.current = &self.current[cnt..];
     }
 }
 
@@ -591,3 +713,189 @@ impl<'a> std::iter::Iterator for NamespaceIterator<'a> {
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use prost::bytes::Buf;
+
+    use super::{
+        bufs_eq, read_varint, skip_varint, Blob, BlobRef, ErrInvalidVarint, NamespaceGroup, Share,
+        ShareError, ShareParsingError, SHARE_SIZE,
+    };
+    use crate::{
+        test_utils::{compact_shares, sparse_shares, TEST_NAMESPACE},
+        verifier::PFB_NAMESPACE,
+    };
+
+    fn blob_with(data: &[u8]) -> Blob {
+        Blob(
+            sparse_shares(TEST_NAMESPACE, data)
+                .into_iter()
+                .map(|share| Share::new(share.into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_advance_across_shares() {
+        let data: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let blob = blob_with(&data);
+        assert!(blob.0.len() > 2);
+
+        // Skipping more than one share's worth of data at once
+        let mut iter = blob.clone().into_iter();
+        iter.advance(1500);
+        assert_eq!(iter.collect::<Vec<_>>(), &data[1500..]);
+        let mut iter = BlobRef::with(&blob.0).data();
+        iter.advance(1500);
+        assert_eq!(iter.collect::<Vec<_>>(), &data[1500..]);
+
+        // A blob which exactly fills its last share (499 bytes in the start share, 503 in the continuation)
+        let blob = blob_with(&data[..1002]);
+        assert_eq!(blob.0.len(), 2);
+        let mut iter = blob.clone().into_iter();
+        iter.advance(499);
+        iter.advance(503);
+        assert_eq!(iter.remaining(), 0);
+        assert_eq!(iter.next(), None);
+        let mut iter = BlobRef::with(&blob.0).data();
+        iter.advance(499);
+        iter.advance(503);
+        assert_eq!(iter.remaining(), 0);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_blob_readers_agree() {
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let blob = blob_with(&data);
+        assert!(blob.0.len() > 1);
+
+        assert_eq!(&blob.to_bytes()[..], &data[..]);
+        assert_eq!(BlobRef::with(&blob.0).data().collect::<Vec<_>>(), data);
+        assert_eq!(blob.clone().into_iter().collect::<Vec<_>>(), data);
+
+        let mut read = Vec::new();
+        blob.clone().into_iter().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // Reads which cross share boundaries fall back to copying
+        let mut iter = blob.clone().into_iter();
+        let mut copied = Vec::new();
+        while iter.has_remaining() {
+            let len = std::cmp::min(700, iter.remaining());
+            copied.extend_from_slice(&iter.copy_to_bytes(len));
+        }
+        assert_eq!(copied, data);
+    }
+
+    #[test]
+    fn test_single_share_blob_is_not_copied() {
+        let blob = blob_with(b"hello");
+        let bytes = blob.to_bytes();
+        assert_eq!(&bytes[..], b"hello");
+        assert_eq!(bytes.as_ptr(), blob.0[0].data_ref().as_ptr());
+    }
+
+    #[test]
+    fn test_bufs_eq() {
+        let data = vec![7u8; 1200];
+        let blob = blob_with(&data);
+        assert!(bufs_eq(BlobRef::with(&blob.0).data(), &data[..]));
+        assert!(!bufs_eq(BlobRef::with(&blob.0).data(), &data[1..]));
+
+        let mut different = data.clone();
+        different[1000] = 8;
+        assert!(!bufs_eq(BlobRef::with(&blob.0).data(), &different[..]));
+    }
+
+    #[test]
+    fn test_truncated_varints() {
+        assert_eq!(skip_varint(&[][..]), Err(ErrInvalidVarint));
+        assert_eq!(skip_varint(&[0x80, 0x80][..]), Err(ErrInvalidVarint));
+        assert!(read_varint(&[0x80][..]).is_err());
+        assert_eq!(skip_varint(&[0x81, 0x01][..]), Ok(2));
+        assert_eq!(read_varint(&[0x81, 0x01][..]).unwrap(), (129, 2));
+    }
+
+    #[test]
+    fn test_malformed_share_input() {
+        // Unpadded base64 of the same length as an encoded share decodes to too many bytes
+        let long = base64::encode(vec![0u8; SHARE_SIZE + 1]);
+        assert!(serde_json::from_str::<Share>(&format!("\"{}\"", long)).is_err());
+        assert!(serde_json::from_str::<Share>("\"AAAA\"").is_err());
+
+        assert_eq!(
+            NamespaceGroup::from_b64(""),
+            Ok(NamespaceGroup::Sparse(vec![]))
+        );
+        let mut unsupported_version = sparse_shares(TEST_NAMESPACE, b"data").remove(0);
+        unsupported_version[8] |= 0x02;
+        assert_eq!(
+            NamespaceGroup::from_b64(&base64::encode(&unsupported_version)),
+            Err(ShareParsingError::ErrUnsupportedVersion)
+        );
+        assert_eq!(
+            NamespaceGroup::from_shares(vec![vec![0u8; 100]]),
+            Err(ShareParsingError::ErrWrongLength)
+        );
+    }
+
+    #[test]
+    fn test_malformed_blobs() {
+        // A blob must begin with a start share
+        let blob = blob_with(&[1u8; 1200]);
+        assert_eq!(
+            BlobRef::with(&blob.0[1..]).try_data().err(),
+            Some(ShareError::NotAStartShare)
+        );
+        assert_eq!(
+            BlobRef::with(&[]).try_data().err(),
+            Some(ShareError::NotAStartShare)
+        );
+
+        // A blob which claims more data than its shares hold is rejected
+        let mut shares = sparse_shares(TEST_NAMESPACE, &[2u8; 100]);
+        shares[0][9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        let blob = Blob(shares.into_iter().map(|s| Share::new(s.into())).collect());
+        assert_eq!(
+            BlobRef::with(&blob.0).try_data().err(),
+            Some(ShareError::SequenceTooLong)
+        );
+
+        // Even by a single byte
+        let shares = sparse_shares(TEST_NAMESPACE, &[2u8; 1000]);
+        let mut blob = Blob(shares.into_iter().map(|s| Share::new(s.into())).collect());
+        let available: usize = blob.0.iter().map(|share| share.data_ref().len()).sum();
+        let data: Vec<u8> = BlobRef::with(&blob.0).data().collect();
+        assert_eq!(data.len(), 1000);
+        let mut first = blob.0[0].as_serialized().to_vec();
+        first[9..13].copy_from_slice(&(available as u32 + 1).to_be_bytes());
+        blob.0[0] = Share::new(first.into());
+        assert_eq!(
+            BlobRef::with(&blob.0).try_data().err(),
+            Some(ShareError::SequenceTooLong)
+        );
+        first = blob.0[0].as_serialized().to_vec();
+        first[9..13].copy_from_slice(&(available as u32).to_be_bytes());
+        blob.0[0] = Share::new(first.into());
+        assert_eq!(BlobRef::with(&blob.0).data().count(), available);
+    }
+
+    #[test]
+    fn test_tx_start_past_end_of_share() {
+        let data = [vec![5u8], vec![0u8; 5]].concat();
+        let share = Share::new(compact_shares(PFB_NAMESPACE, &data, &[0]).remove(0).into());
+        assert!(share.is_valid_tx_start(0));
+        assert!(share.is_valid_tx_start(6));
+        assert!(!share.is_valid_tx_start(3));
+
+        // A length prefix which runs past the end of the share
+        let data = [vec![0xff, 0xff, 0x03], vec![0u8; 5]].concat();
+        let share = Share::new(compact_shares(PFB_NAMESPACE, &data, &[0]).remove(0).into());
+        assert!(share.is_valid_tx_start(0));
+        assert!(!share.is_valid_tx_start(100));
+    }
+}
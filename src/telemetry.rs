@@ -0,0 +1,117 @@
+//! Metrics recorded through the [`metrics`](https://docs.rs/metrics) facade when the `metrics` feature is
+//! enabled. The application chooses how to export them, e.g. with a Prometheus exporter. Without the feature,
+//! every function in this module is a no-op.
+#![cfg_attr(not(feature = "native"), allow(dead_code))]
+
+pub(crate) use recorder::*;
+
+#[cfg(feature = "metrics")]
+mod recorder {
+    use std::time::Duration;
+
+    use metrics::{counter, histogram};
+
+    use crate::verifier::compact::CompactEncode;
+
+    /// The size of a single share, in bytes
+    const SHARE_SIZE: u64 = 512;
+
+    /// Records the duration of a single Celestia RPC call, labelled by method and outcome
+    pub(crate) fn record_rpc_call(method: &str, duration: Duration, success: bool) {
+        let status = if success { "ok" } else { "error" };
+        histogram!(
+            "celestia_rpc_call_duration_seconds",
+            duration.as_secs_f64(),
+            "method" => method.to_string(),
+            "status" => status
+        );
+    }
+
+    /// Records the time taken to fetch a complete block
+    pub(crate) fn record_block_fetch(duration: Duration) {
+        histogram!(
+            "celestia_block_fetch_duration_seconds",
+            duration.as_secs_f64()
+        );
+    }
+
+    /// Records the size of a downloaded extended data square
+    pub(crate) fn record_eds_size(shares: usize) {
+        histogram!("celestia_eds_shares", shares as f64);
+        counter!(
+            "celestia_eds_downloaded_bytes_total",
+            shares as u64 * SHARE_SIZE
+        );
+    }
+
+    /// Records the number of relevant pfbs in a block, and the number of rollup blobs which they pay for
+    pub(crate) fn record_relevant_pfbs(pfbs: usize, blobs: usize) {
+        histogram!("celestia_relevant_pfbs_per_block", pfbs as f64);
+        histogram!("celestia_blobs_per_block", blobs as f64);
+    }
+
+    /// Records the size of a proof in the compact wire format, labelled by the kind of proof
+    pub(crate) fn record_proof_size(kind: &'static str, proof: &impl CompactEncode) {
+        histogram!(
+            "celestia_proof_size_bytes",
+            proof.compact_len() as f64,
+            "kind" => kind
+        );
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod recorder {
+    use std::time::Duration;
+
+    use crate::verifier::compact::CompactEncode;
+
+    pub(crate) fn record_rpc_call(_method: &str, _duration: Duration, _success: bool) {}
+
+    pub(crate) fn record_block_fetch(_duration: Duration) {}
+
+    pub(crate) fn record_eds_size(_shares: usize) {}
+
+    pub(crate) fn record_relevant_pfbs(_pfbs: usize, _blobs: usize) {}
+
+    pub(crate) fn record_proof_size(_kind: &'static str, _proof: &impl CompactEncode) {}
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+    use super::record_proof_size;
+    use crate::{
+        test_utils::single_blob_block,
+        verifier::{compact::CompactEncode, proofs::CompletenessProof},
+    };
+
+    #[test]
+    fn test_proof_size_is_the_compact_length() {
+        // The per-thread recorder can be installed by every test which needs it
+        let _ = DebuggingRecorder::per_thread().install();
+        let (block, _) = single_blob_block(4, vec![1u8; 1000]);
+        let proof = CompletenessProof::from_filtered_block(&block).0;
+        record_proof_size("test", &proof);
+
+        let snapshot = Snapshotter::current_thread_snapshot().expect("metrics were recorded");
+        let sizes: Vec<f64> = snapshot
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| {
+                let key = key.key();
+                key.name() == "celestia_proof_size_bytes"
+                    && key
+                        .labels()
+                        .any(|label| label.key() == "kind" && label.value() == "test")
+            })
+            .flat_map(|(.., value)| match value {
+                DebugValue::Histogram(values) => values,
+                _ => vec![],
+            })
+            .map(|value| value.into_inner())
+            .collect();
+        assert_eq!(sizes, vec![proof.to_compact_bytes().len() as f64]);
+    }
+}
@@ -0,0 +1,564 @@
+//! Helpers for building synthetic Celestia blocks in tests and benchmarks.
+//!
+//! The blocks produced here are internally consistent (row and column roots are computed from
+//! the generated extended data square, and the header's `data_hash` commits to them), but the
+//! parity shares are filler rather than a real Reed-Solomon extension.
+use std::collections::{BTreeMap, BTreeSet};
+
+use nmt_rs::{CelestiaNmt, NamespaceId, NamespacedHash};
+use prost::Message;
+use sovereign_sdk::Bytes;
+use tendermint::{
+    crypto::{default::Sha256, Sha256 as _},
+    merkle::simple_hash_from_byte_vectors,
+};
+
+use crate::{
+    parse_pfb_namespace,
+    pfb::{BlobTx, MsgPayForBlobs, Tx, TxBody},
+    share_commit::recreate_commitment,
+    shares::{Blob, BlobRef, NamespaceGroup, Share},
+    types::{ExtendedDataSquare, FilteredCelestiaBlock, RelevantPfb, RollupNamespaceData, Row},
+    verifier::{address::CelestiaAddress, PARITY_SHARES_NAMESPACE, PFB_NAMESPACE},
+    BlobMetadata, BlobWithSender, CelestiaHeader, CompactHeader, DataAvailabilityHeader,
+    ExtendedHeader, ProtobufHash,
+};
+
+const HEADER_RESPONSE_JSON: &[u8] = include_bytes!("./header_response.json");
+
+/// The namespace used by the test rollup (b"sov-test")
+pub const TEST_NAMESPACE: NamespaceId = NamespaceId([115, 111, 118, 45, 116, 101, 115, 116]);
+/// The namespace containing ordinary (non-pfb) cosmos sdk transactions
+pub const TX_NAMESPACE: NamespaceId = NamespaceId(hex_literal::hex!("0000000000000001"));
+/// The namespace used to pad the end of the data square
+pub const TAIL_PADDING_NAMESPACE: NamespaceId = NamespaceId(hex_literal::hex!("fffffffffffffffe"));
+
+const SHARE_SIZE: usize = 512;
+const PFB_TYPE_URL: &str = "/celestia.blob.v1.MsgPayForBlobs";
+
+/// A blob to be included in a synthetic block
+#[derive(Debug, Clone)]
+pub struct TestBlob {
+    pub namespace: NamespaceId,
+    pub signer: String,
+    pub data: Vec<u8>,
+    /// Whether the block contains a pfb paying for the blob. Only a faulty node serves unpaid blobs.
+    pub paid: bool,
+}
+
+impl TestBlob {
+    pub fn new(namespace: NamespaceId, signer: &str, data: Vec<u8>) -> Self {
+        Self {
+            namespace,
+            signer: signer.to_string(),
+            data,
+            paid: true,
+        }
+    }
+
+    /// A blob in the test rollup's namespace
+    pub fn rollup(signer: &str, data: Vec<u8>) -> Self {
+        Self::new(TEST_NAMESPACE, signer, data)
+    }
+
+    /// Leaves the blob without a pfb paying for it
+    pub fn unpaid(mut self) -> Self {
+        self.paid = false;
+        self
+    }
+}
+
+/// Builds a [`FilteredCelestiaBlock`] from a list of blobs.
+///
+/// Shares are laid out in namespace order: the PFB namespace first, followed by the blobs
+/// (sorted by namespace), followed by tail padding to fill the original data square.
+pub struct BlockBuilder {
+    /// The width of the original (non-extended) data square
+    ods_width: usize,
+    /// The number of shares of ordinary transactions which precede the pfb namespace
+    tx_shares: usize,
+    blobs: Vec<TestBlob>,
+}
+
+impl BlockBuilder {
+    pub fn new(ods_width: usize) -> Self {
+        assert!(
+            ods_width.is_power_of_two(),
+            "square width must be a power of two"
+        );
+        Self {
+            ods_width,
+            tx_shares: 0,
+            blobs: Vec::new(),
+        }
+    }
+
+    /// Fill the start of the square with `count` shares of ordinary transactions, so that the pfb
+    /// namespace does not begin at share 0
+    pub fn with_tx_shares(mut self, count: usize) -> Self {
+        self.tx_shares = count;
+        self
+    }
+
+    pub fn with_blob(mut self, blob: TestBlob) -> Self {
+        self.blobs.push(blob);
+        self
+    }
+
+    /// Builds the block. Returns the filtered block for `rollup_namespace` along with the
+    /// transactions that a correct DA service would extract from it.
+    pub fn build(
+        self,
+        rollup_namespace: NamespaceId,
+    ) -> (FilteredCelestiaBlock, Vec<BlobWithSender>) {
+        self.build_for_namespaces(&BTreeSet::from([rollup_namespace]))
+    }
+
+    /// Builds the block, filtered for all of the given rollup namespaces
+    pub fn build_for_namespaces(
+        self,
+        rollup_namespaces: &BTreeSet<NamespaceId>,
+    ) -> (FilteredCelestiaBlock, Vec<BlobWithSender>) {
+        let (block, txs, _) = self.build_with_eds(rollup_namespaces);
+        (block, txs)
+    }
+
+    /// Builds the block, along with the complete extended data square which a node would serve for it
+    pub fn build_with_eds(
+        self,
+        rollup_namespaces: &BTreeSet<NamespaceId>,
+    ) -> (
+        FilteredCelestiaBlock,
+        Vec<BlobWithSender>,
+        ExtendedDataSquare,
+    ) {
+        let eds_width = self.ods_width * 2;
+        let mut blobs = self.blobs;
+        blobs.sort_by_key(|blob| blob.namespace);
+
+        // Split every blob into shares and compute its commitment
+        let mut blob_shares = Vec::with_capacity(blobs.len());
+        let mut pfb_txs = Vec::with_capacity(blobs.len());
+        let mut blob_metadata = Vec::with_capacity(blobs.len());
+        for blob in blobs.iter() {
+            let shares: Vec<Share> = sparse_shares(blob.namespace, &blob.data)
+                .into_iter()
+                .map(|share| Share::new(Bytes::from(share)))
+                .collect();
+            let commitment = recreate_commitment(eds_width, BlobRef::with(&shares))
+                .expect("test blob must fit in the square");
+            let (pfb_tx, pfb_tx_hash) = wrapped_pfb(blob, &commitment);
+            if blob.paid {
+                pfb_txs.push(pfb_tx);
+            }
+            blob_metadata.push((commitment, pfb_tx_hash));
+            blob_shares.push(shares);
+        }
+
+        // Serialize the PFBs as a compact sequence of length-prefixed txs
+        let mut pfb_data = Vec::new();
+        let mut unit_starts = Vec::with_capacity(pfb_txs.len());
+        for tx in pfb_txs.iter() {
+            unit_starts.push(pfb_data.len());
+            prost::encoding::encode_varint(tx.len() as u64, &mut pfb_data);
+            pfb_data.extend_from_slice(tx);
+        }
+        let pfb_shares: Vec<Share> = if pfb_txs.is_empty() {
+            vec![]
+        } else {
+            compact_shares(PFB_NAMESPACE, &pfb_data, &unit_starts)
+                .into_iter()
+                .map(|share| Share::new(Bytes::from(share)))
+                .collect()
+        };
+
+        // Lay out the original data square
+        let mut ods: Vec<Share> = Vec::new();
+        if self.tx_shares != 0 {
+            // Size the data so that it fills exactly `tx_shares` compact shares
+            let tx_data = vec![0u8; 495 + (self.tx_shares - 1) * 499];
+            ods.extend(
+                compact_shares(TX_NAMESPACE, &tx_data, &[0])
+                    .into_iter()
+                    .map(|share| Share::new(Bytes::from(share))),
+            );
+        }
+        ods.extend(pfb_shares.iter().cloned());
+        let mut rollup_shares: BTreeMap<NamespaceId, Vec<Share>> = rollup_namespaces
+            .iter()
+            .map(|namespace| (*namespace, Vec::new()))
+            .collect();
+        let mut expected_txs = Vec::new();
+        for ((blob, shares), (commitment, pfb_tx_hash)) in
+            blobs.iter().zip(blob_shares.into_iter()).zip(blob_metadata)
+        {
+            if let Some(namespace_shares) = rollup_shares.get_mut(&blob.namespace) {
+                namespace_shares.extend(shares.iter().cloned());
+            }
+            if rollup_namespaces.contains(&blob.namespace) && blob.paid {
+                expected_txs.push(BlobWithSender {
+                    blob: Blob(shares.clone()),
+                    sender: CelestiaAddress(blob.signer.as_bytes().to_vec()),
+                    metadata: BlobMetadata::new(
+                        blob.namespace,
+                        ods.len(),
+                        self.ods_width,
+                        commitment,
+                        pfb_tx_hash,
+                    ),
+                });
+            }
+            ods.extend(shares);
+        }
+        assert!(
+            ods.len() <= self.ods_width * self.ods_width,
+            "blobs do not fit in a square of width {}",
+            self.ods_width
+        );
+        let padding = Share::new(Bytes::from(
+            sparse_shares(TAIL_PADDING_NAMESPACE, &[]).remove(0),
+        ));
+        ods.resize(self.ods_width * self.ods_width, padding);
+
+        // Extend the square. Parity shares are filler, since only their namespace is inspected.
+        let parity = {
+            let mut raw = vec![0u8; SHARE_SIZE];
+            raw[..8].copy_from_slice(&PARITY_SHARES_NAMESPACE.0);
+            Share::new(Bytes::from(raw))
+        };
+        let mut eds_rows: Vec<Vec<Share>> = Vec::with_capacity(eds_width);
+        for row_idx in 0..eds_width {
+            let mut row = Vec::with_capacity(eds_width);
+            for col_idx in 0..eds_width {
+                if row_idx < self.ods_width && col_idx < self.ods_width {
+                    row.push(ods[row_idx * self.ods_width + col_idx].clone());
+                } else {
+                    row.push(parity.clone());
+                }
+            }
+            eds_rows.push(row);
+        }
+
+        let row_roots: Vec<NamespacedHash> = eds_rows
+            .iter()
+            .map(|row| axis_root(row.iter(), self.ods_width))
+            .collect();
+        let column_roots: Vec<NamespacedHash> = (0..eds_width)
+            .map(|col_idx| axis_root(eds_rows.iter().map(|row| &row[col_idx]), self.ods_width))
+            .collect();
+
+        let mut header: CompactHeader = {
+            let response: ExtendedHeader = serde_json::from_slice(HEADER_RESPONSE_JSON)
+                .expect("header fixture must deserialize");
+            response.header.into()
+        };
+        let data_root = {
+            let roots: Vec<&NamespacedHash> = row_roots.iter().chain(column_roots.iter()).collect();
+            simple_hash_from_byte_vectors::<Sha256>(&roots)
+        };
+        header.data_hash = Some(ProtobufHash(data_root));
+        let dah = DataAvailabilityHeader {
+            row_roots,
+            column_roots,
+        };
+
+        let rows_containing = |nid: NamespaceId| -> Vec<Row> {
+            eds_rows
+                .iter()
+                .zip(dah.row_roots.iter())
+                .filter(|(_, root)| root.contains(nid))
+                .map(|(shares, root)| Row {
+                    shares: shares.clone(),
+                    root: root.clone(),
+                })
+                .collect()
+        };
+        let rollup_data = rollup_shares
+            .into_iter()
+            .map(|(namespace, shares)| RollupNamespaceData {
+                namespace,
+                data: NamespaceGroup::Sparse(shares),
+                rows: match dah.absence_row(namespace) {
+                    Some(row_idx) => vec![Row {
+                        shares: eds_rows[row_idx].clone(),
+                        root: dah.row_roots[row_idx].clone(),
+                    }],
+                    None => rows_containing(namespace),
+                },
+            })
+            .collect();
+        let pfb_rows = rows_containing(PFB_NAMESPACE);
+
+        let relevant_pfbs = RelevantPfb::filter(
+            parse_pfb_namespace(NamespaceGroup::Compact(pfb_shares))
+                .expect("test pfbs must be valid"),
+            rollup_namespaces,
+        );
+
+        let block = FilteredCelestiaBlock::new(
+            CelestiaHeader::new(dah, header),
+            rollup_data,
+            relevant_pfbs,
+            pfb_rows,
+        );
+        let eds = ExtendedDataSquare {
+            data_square: eds_rows.into_iter().flatten().collect(),
+            codec: "Leopard".to_string(),
+        };
+        (block, expected_txs, eds)
+    }
+}
+
+/// Builds a block whose only blob is `data`, sent to the test rollup by `celestia1alice`
+pub fn single_blob_block(
+    ods_width: usize,
+    data: Vec<u8>,
+) -> (FilteredCelestiaBlock, Vec<BlobWithSender>) {
+    BlockBuilder::new(ods_width)
+        .with_blob(TestBlob::rollup("celestia1alice", data))
+        .build(TEST_NAMESPACE)
+}
+
+/// Computes the root of a row or column of the extended data square. Shares in the original
+/// data square keep their namespace, while all other shares are parity shares.
+fn axis_root<'a>(shares: impl Iterator<Item = &'a Share>, ods_width: usize) -> NamespacedHash {
+    let mut nmt = CelestiaNmt::new();
+    for (idx, share) in shares.enumerate() {
+        let namespace = if idx < ods_width {
+            share.namespace()
+        } else {
+            PARITY_SHARES_NAMESPACE
+        };
+        nmt.push_leaf(share.as_serialized(), namespace)
+            .expect("shares are pushed in order");
+    }
+    nmt.root()
+}
+
+/// Wraps a PFB for the provided blob in a `BlobTx`, the way celestia-app does. Returns the encoded
+/// `BlobTx` along with the hash of the inner transaction.
+fn wrapped_pfb(blob: &TestBlob, commitment: &[u8; 32]) -> (Vec<u8>, [u8; 32]) {
+    let pfb = MsgPayForBlobs {
+        signer: blob.signer.clone(),
+        namespace_ids: vec![Bytes::copy_from_slice(&blob.namespace.0)],
+        blob_sizes: vec![blob.data.len() as u32],
+        share_commitments: vec![Bytes::copy_from_slice(commitment)],
+        share_versions: vec![0],
+    };
+    let tx = Tx {
+        body: Some(TxBody {
+            messages: vec![prost_types::Any {
+                type_url: PFB_TYPE_URL.to_string(),
+                value: pfb.encode_to_vec(),
+            }],
+        }),
+    };
+    let tx = tx.encode_to_vec();
+    let tx_hash = Sha256::digest(&tx);
+    let blob_tx = BlobTx { tx: tx.into() }.encode_to_vec();
+    (blob_tx, tx_hash)
+}
+
+/// Splits `data` into sparse shares (the format used for blobs)
+pub fn sparse_shares(namespace: NamespaceId, data: &[u8]) -> Vec<Vec<u8>> {
+    let mut shares = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let is_start = shares.is_empty();
+        let mut share = Vec::with_capacity(SHARE_SIZE);
+        share.extend_from_slice(&namespace.0);
+        share.push(is_start as u8);
+        if is_start {
+            share.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+        let end = std::cmp::min(cursor + SHARE_SIZE - share.len(), data.len());
+        share.extend_from_slice(&data[cursor..end]);
+        share.resize(SHARE_SIZE, 0);
+        shares.push(share);
+        cursor = end;
+        if cursor == data.len() {
+            return shares;
+        }
+    }
+}
+
+/// Splits `data` into compact shares (the format used for reserved namespaces). `unit_starts`
+/// contains the offset into `data` at which each unit (i.e. each length-prefixed tx) begins.
+pub fn compact_shares(namespace: NamespaceId, data: &[u8], unit_starts: &[usize]) -> Vec<Vec<u8>> {
+    let mut shares = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let is_start = shares.is_empty();
+        let mut share = Vec::with_capacity(SHARE_SIZE);
+        share.extend_from_slice(&namespace.0);
+        share.push(is_start as u8);
+        if is_start {
+            share.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+        // The reserved bytes hold the index (into the share) of the first unit starting in it
+        let header_len = share.len() + 4;
+        let end = std::cmp::min(cursor + SHARE_SIZE - header_len, data.len());
+        let first_unit = unit_starts
+            .iter()
+            .find(|start| **start >= cursor && **start < end)
+            .map(|start| (start - cursor + header_len) as u32)
+            .unwrap_or(0);
+        share.extend_from_slice(&first_unit.to_be_bytes());
+        share.extend_from_slice(&data[cursor..end]);
+        share.resize(SHARE_SIZE, 0);
+        shares.push(share);
+        cursor = end;
+        if cursor == data.len() {
+            return shares;
+        }
+    }
+}
+
+/// The header fixture as it is returned by the rpc server, with its height replaced by `height`
+pub fn header_json(height: u64) -> serde_json::Value {
+    let mut header: serde_json::Value =
+        serde_json::from_slice(HEADER_RESPONSE_JSON).expect("header fixture must be valid json");
+    header["header"]["height"] = height.to_string().into();
+    header
+}
+
+/// Responds to the header calls of a [`MockNode`] whose head is at `head`, serving the header fixture at every
+/// height. Other calls fail.
+pub fn header_responses(
+    head: u64,
+) -> impl Fn(&str, &serde_json::Value) -> Option<serde_json::Value> + Send + Sync + 'static {
+    move |method: &str, params: &serde_json::Value| match method {
+        "header.LocalHead" => Some(header_json(head)),
+        "header.GetByHeight" => params[0].as_u64().map(header_json),
+        _ => None,
+    }
+}
+
+/// Like [`header_responses`], but serves headers from a fork with a different chain id, so their hashes never
+/// match those of the fixture
+pub fn forked_header_responses(
+    head: u64,
+) -> impl Fn(&str, &serde_json::Value) -> Option<serde_json::Value> + Send + Sync + 'static {
+    let respond = header_responses(head);
+    move |method: &str, params: &serde_json::Value| {
+        let mut header = respond(method, params)?;
+        header["header"]["chain_id"] = "forked".into();
+        Some(header)
+    }
+}
+
+#[cfg(feature = "native")]
+pub use mock_node::MockNode;
+
+#[cfg(feature = "native")]
+mod mock_node {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    type Respond = dyn Fn(&str, &Value) -> Option<Value> + Send + Sync;
+
+    /// A minimal json-rpc server over http, which answers each call with `respond(method, params)`. A `None`
+    /// response is sent as an http 503, which clients see as a transport failure.
+    pub struct MockNode {
+        pub url: String,
+        calls: Arc<Mutex<Vec<String>>>,
+        server: JoinHandle<()>,
+    }
+
+    impl MockNode {
+        pub async fn start(
+            respond: impl Fn(&str, &Value) -> Option<Value> + Send + Sync + 'static,
+        ) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let respond: Arc<Respond> = Arc::new(respond);
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let server = {
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        tokio::spawn(serve(stream, respond.clone(), calls.clone()));
+                    }
+                })
+            };
+            Self { url, calls, server }
+        }
+
+        /// Returns the number of calls to `method` which the node has received
+        pub fn calls(&self, method: &str) -> usize {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().filter(|call| *call == method).count()
+        }
+    }
+
+    impl Drop for MockNode {
+        fn drop(&mut self) {
+            self.server.abort();
+        }
+    }
+
+    /// Answers the requests sent over a single (keep-alive) connection until the client closes it
+    async fn serve(mut stream: TcpStream, respond: Arc<Respond>, calls: Arc<Mutex<Vec<String>>>) {
+        let mut buf = Vec::new();
+        loop {
+            let head_len = loop {
+                if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+                if !read_more(&mut stream, &mut buf).await {
+                    return;
+                }
+            };
+            let head = String::from_utf8_lossy(&buf[..head_len]).to_ascii_lowercase();
+            let body_len = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|len| len.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            while buf.len() < head_len + body_len {
+                if !read_more(&mut stream, &mut buf).await {
+                    return;
+                }
+            }
+            let request: Value = serde_json::from_slice(&buf[head_len..head_len + body_len])
+                .expect("requests must be valid json");
+            buf.drain(..head_len + body_len);
+
+            let method = request["method"].as_str().unwrap_or_default().to_string();
+            calls.lock().unwrap().push(method.clone());
+            let response = match respond(&method, &request["params"]) {
+                Some(result) => {
+                    let body = json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                        .to_string();
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                }
+                None => "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n".to_string(),
+            };
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Reads from `stream` into `buf`, returning false once the connection is closed
+    async fn read_more(stream: &mut TcpStream, buf: &mut Vec<u8>) -> bool {
+        let mut chunk = [0u8; 4096];
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => false,
+            Ok(read) => {
+                buf.extend_from_slice(&chunk[..read]);
+                true
+            }
+        }
+    }
+}
@@ -1,19 +1,24 @@
-use std::collections::HashMap;
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use anyhow::ensure;
 use borsh::{BorshDeserialize, BorshSerialize};
 use serde::{Deserialize, Serialize};
-use sovereign_sdk::{services::da::SlotData, Bytes};
+use sovereign_sdk::services::da::SlotData;
 use tendermint::{crypto::default::Sha256, merkle};
 
 pub use nmt_rs::NamespaceId;
 
 use crate::{
+    cid::{ns_hash_from_cid, CidError},
     pfb::MsgPayForBlobs,
-    shares::{NamespaceGroup, Share},
+    share_commit::recreate_commitment,
+    shares::{NamespaceGroup, Share, B64_SHARE_SIZE},
     utils::BoxError,
-    verifier::PARITY_SHARES_NAMESPACE,
-    CelestiaHeader, TxPosition,
+    verifier::{PARITY_SHARES_NAMESPACE, PFB_NAMESPACE},
+    CelestiaHeader, ParsedPfb, TxPosition,
 };
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -52,6 +57,13 @@ impl ExtendedDataSquare {
         Ok(square_size)
     }
 
+    /// Estimates the size in bytes of the json response holding a square of the given width. Each share
+    /// is sent as a quoted base64 string followed by a comma.
+    pub fn estimated_response_size(square_size: usize) -> u64 {
+        let shares = (square_size * square_size) as u64;
+        shares * (B64_SHARE_SIZE as u64 + 3) + 64
+    }
+
     pub fn rows(&self) -> Result<Vec<&[Share]>, BoxError> {
         let square_size = self.square_size()?;
 
@@ -65,17 +77,100 @@ impl ExtendedDataSquare {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
 pub struct FilteredCelestiaBlock {
     pub header: CelestiaHeader,
-    pub rollup_data: NamespaceGroup,
-    /// A mapping from blob commitment to the PFB containing that commitment
-    /// for each blob addressed to the rollup namespace
-    pub relevant_pfbs: HashMap<Bytes, (MsgPayForBlobs, TxPosition)>,
-    /// All rows in the extended data square which contain rollup data
-    pub rollup_rows: Vec<Row>,
+    /// The data of each of the rollup's namespaces, sorted by namespace
+    pub rollup_data: Vec<RollupNamespaceData>,
+    /// Every PFB which pays for at least one blob addressed to a rollup namespace,
+    /// in the order in which they appear in the square
+    pub relevant_pfbs: Vec<RelevantPfb>,
     /// All rows in the extended data square which contain pfb data
     pub pfb_rows: Vec<Row>,
+    #[borsh_skip]
+    #[serde(skip)]
+    row_trees: RowTrees,
+}
+
+impl PartialEq for FilteredCelestiaBlock {
+    /// The row trees are derived from the rows, so two blocks are equal whether or not their trees were built
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header
+            && self.rollup_data == other.rollup_data
+            && self.relevant_pfbs == other.relevant_pfbs
+            && self.pfb_rows == other.pfb_rows
+    }
+}
+
+/// The namespaced merkle trees of all of a block's rows, keyed by row root. The trees are built at most once and
+/// are never serialized. Clones of a block share the trees which were already built.
+#[derive(Default, Clone)]
+struct RowTrees(OnceLock<Arc<Vec<(NamespacedHash, Mutex<CelestiaNmt>)>>>);
+
+impl std::fmt::Debug for RowTrees {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.get() {
+            Some(trees) => write!(f, "RowTrees({} built)", trees.len()),
+            None => write!(f, "RowTrees(not built)"),
+        }
+    }
+}
+
+/// The shares of a single rollup namespace, along with the rows needed to prove their completeness
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct RollupNamespaceData {
+    pub namespace: NamespaceId,
+    pub data: NamespaceGroup,
+    /// All rows in the extended data square which contain data from this namespace. If there is no such
+    /// data, holds the single row in which it would have been stored, so that its absence can be proven.
+    pub rows: Vec<Row>,
+}
+
+/// A PFB which pays for at least one blob in a rollup namespace
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct RelevantPfb {
+    pub pfb: MsgPayForBlobs,
+    pub position: TxPosition,
+    /// The hash of the cosmos sdk transaction containing the PFB
+    pub tx_hash: [u8; 32],
+    /// The indices (into `pfb.namespace_ids`) of the blobs which were sent to a rollup namespace
+    pub blob_indices: Vec<usize>,
+}
+
+impl RelevantPfb {
+    /// Selects the PFBs which pay for at least one blob in any of `namespaces`, preserving their order
+    pub fn filter(pfbs: Vec<ParsedPfb>, namespaces: &BTreeSet<NamespaceId>) -> Vec<Self> {
+        pfbs.into_iter()
+            .filter_map(
+                |ParsedPfb {
+                     pfb,
+                     position,
+                     tx_hash,
+                 }| {
+                    let blob_indices: Vec<usize> = pfb
+                        .namespace_ids
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, nid)| {
+                            namespaces
+                                .iter()
+                                .any(|namespace| nid[..] == namespace.0[..])
+                        })
+                        .map(|(idx, _)| idx)
+                        .collect();
+                    if blob_indices.is_empty() {
+                        return None;
+                    }
+                    Some(Self {
+                        pfb,
+                        position,
+                        tx_hash,
+                        blob_indices,
+                    })
+                },
+            )
+            .collect()
+    }
 }
 
 impl SlotData for FilteredCelestiaBlock {
@@ -94,10 +189,131 @@ impl SlotData for FilteredCelestiaBlock {
 }
 
 impl FilteredCelestiaBlock {
+    pub fn new(
+        header: CelestiaHeader,
+        rollup_data: Vec<RollupNamespaceData>,
+        relevant_pfbs: Vec<RelevantPfb>,
+        pfb_rows: Vec<Row>,
+    ) -> Self {
+        Self {
+            header,
+            rollup_data,
+            relevant_pfbs,
+            pfb_rows,
+            row_trees: RowTrees::default(),
+        }
+    }
+
+    /// Builds the trees of every row held by the block, unless they were already built. With the `parallel`
+    /// feature, the trees are built concurrently.
+    pub fn merklize_rows(&self) {
+        self.row_trees.0.get_or_init(|| {
+            let mut rows: Vec<&Row> = Vec::new();
+            let rollup_rows = self.rollup_data.iter().flat_map(|data| data.rows.iter());
+            for row in self.pfb_rows.iter().chain(rollup_rows) {
+                if !rows.iter().any(|other| other.root == row.root) {
+                    rows.push(row);
+                }
+            }
+
+            #[cfg(feature = "parallel")]
+            let trees: Vec<CelestiaNmt> = {
+                use rayon::prelude::*;
+                rows.par_iter().map(|row| row.merklized()).collect()
+            };
+            #[cfg(not(feature = "parallel"))]
+            let trees: Vec<CelestiaNmt> = rows.iter().map(|row| row.merklized()).collect();
+
+            let trees = rows
+                .into_iter()
+                .map(|row| row.root.clone())
+                .zip(trees.into_iter().map(Mutex::new))
+                .collect();
+            Arc::new(trees)
+        });
+    }
+
+    /// Runs `f` on the tree of the given row. The tree is taken from those built by [`Self::merklize_rows`],
+    /// or built on the spot if the row isn't among them.
+    pub fn with_row_tree<T>(&self, row: &Row, f: impl FnOnce(&mut CelestiaNmt) -> T) -> T {
+        let cached = self
+            .row_trees
+            .0
+            .get()
+            .and_then(|trees| trees.iter().find(|(root, _)| root == &row.root));
+        match cached {
+            Some((_, tree)) => f(&mut tree.lock().unwrap()),
+            None => f(&mut row.merklized()),
+        }
+    }
+
     pub fn square_size(&self) -> usize {
         self.header.square_size()
     }
 
+    /// Returns the PFB which paid for each blob in any rollup namespace, along with the index of
+    /// the blob within that PFB.
+    pub fn blob_pfbs(&self) -> impl Iterator<Item = (&RelevantPfb, usize)> {
+        self.relevant_pfbs.iter().flat_map(|relevant_pfb| {
+            relevant_pfb
+                .blob_indices
+                .iter()
+                .map(move |blob_idx| (relevant_pfb, *blob_idx))
+        })
+    }
+
+    /// Returns the PFB which paid for each blob in `namespace`, along with the index of the blob within
+    /// that PFB. Since blobs are stored in the same order as the PFBs which pay for them, the n-th item
+    /// corresponds to the n-th blob of the namespace.
+    pub fn namespace_blob_pfbs(
+        &self,
+        namespace: NamespaceId,
+    ) -> impl Iterator<Item = (&RelevantPfb, usize)> {
+        self.blob_pfbs().filter(move |(relevant_pfb, blob_idx)| {
+            relevant_pfb.pfb.namespace_ids[*blob_idx][..] == namespace.0[..]
+        })
+    }
+
+    /// Checks that the n-th blob of each rollup namespace is the blob committed to by the n-th PFB which pays
+    /// for a blob in that namespace, and that no PFB pays for a blob which is missing
+    pub fn check_blob_pfbs(&self) -> Result<(), BoxError> {
+        for data in &self.rollup_data {
+            let namespace = hex::encode(data.namespace);
+            let mut blob_pfbs = self.namespace_blob_pfbs(data.namespace);
+            for (idx, blob) in data.data.blobs().enumerate() {
+                let (relevant_pfb, blob_idx) = blob_pfbs.next().ok_or_else(|| {
+                    anyhow::format_err!("blob {} of namespace 0x{} has no pfb", idx, namespace)
+                })?;
+                // Blobs are read by the rollup without checking their length again
+                blob.try_data()?;
+                let commitment = recreate_commitment(self.square_size(), blob)?;
+                ensure!(
+                    relevant_pfb
+                        .pfb
+                        .share_commitments
+                        .get(blob_idx)
+                        .map_or(false, |expected| expected[..] == commitment[..]),
+                    "blob {} of namespace 0x{} doesn't match the commitment of its pfb",
+                    idx,
+                    namespace
+                );
+            }
+            ensure!(
+                blob_pfbs.next().is_none(),
+                "a pfb pays for a blob missing from namespace 0x{}",
+                namespace
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the data of the given rollup namespace, if the block was filtered for it
+    pub fn rollup_namespace(&self, namespace: NamespaceId) -> Option<&RollupNamespaceData> {
+        self.rollup_data
+            .iter()
+            .find(|data| data.namespace == namespace)
+    }
+
     pub fn get_row_number(&self, share_idx: usize) -> usize {
         share_idx / self.square_size()
     }
@@ -112,6 +328,45 @@ impl FilteredCelestiaBlock {
     pub fn col_root_for_share(&self, share_idx: usize) -> &NamespacedHash {
         &self.header.dah.column_roots[self.get_col_number(share_idx)]
     }
+
+    /// Returns the index of the first row whose root contains `namespace`
+    fn first_row_containing(&self, namespace: NamespaceId) -> Option<usize> {
+        self.header
+            .dah
+            .row_roots
+            .iter()
+            .position(|root| root.contains(namespace))
+    }
+
+    /// Returns the row with the given index if it contains pfb data
+    pub fn pfb_row(&self, row_idx: usize) -> Option<&Row> {
+        let first_pfb_row = self.first_row_containing(PFB_NAMESPACE)?;
+        self.pfb_rows.get(row_idx.checked_sub(first_pfb_row)?)
+    }
+
+    /// Returns the index (in the original data square) of the first share of `namespace`. `rows` must be
+    /// the rows whose roots contain the namespace.
+    fn namespace_start(&self, rows: &[Row], namespace: NamespaceId) -> Option<usize> {
+        let first_row = self.first_row_containing(namespace)?;
+        let ods_width = self.square_size() / 2;
+        rows.iter().enumerate().find_map(|(offset, row)| {
+            let col_idx = row.shares[..ods_width]
+                .iter()
+                .position(|share| share.namespace() == namespace)?;
+            Some((first_row + offset) * ods_width + col_idx)
+        })
+    }
+
+    /// Returns the index (in the original data square) of the first share in the pfb namespace.
+    pub fn pfb_namespace_start(&self) -> Option<usize> {
+        self.namespace_start(&self.pfb_rows, PFB_NAMESPACE)
+    }
+
+    /// Returns the index (in the original data square) of the first share in the given rollup namespace.
+    pub fn rollup_namespace_start(&self, rollup_namespace: NamespaceId) -> Option<usize> {
+        let data = self.rollup_namespace(rollup_namespace)?;
+        self.namespace_start(&data.rows, rollup_namespace)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -122,10 +377,17 @@ pub enum ValidationError {
     MissingTx,
     InvalidRowProof,
     InvalidSigner,
+    InvalidBlobMetadata,
+    MismatchedRootCount,
+    InvalidSquareSize,
+    InvalidNamespaceRange,
+    MisplacedParityRoot,
+    UnsortedRowRoots,
 }
 
 impl CelestiaHeader {
     pub fn validate_dah(&self) -> Result<(), ValidationError> {
+        self.dah.validate_structure()?;
         let rows_iter = self.dah.row_roots.iter();
         let cols_iter = self.dah.column_roots.iter();
         let byte_vecs: Vec<&NamespacedHash> = rows_iter.chain(cols_iter).collect();
@@ -149,19 +411,25 @@ pub struct Row {
 }
 
 impl Row {
-    pub fn merklized(&self) -> CelestiaNmt {
+    /// Builds the tree of a row of the extended data square from its shares. Returns `None` if the namespaces
+    /// of the shares in the left half of the row are out of order, which is never the case in a valid square.
+    pub fn tree_of(shares: &[Share]) -> Option<CelestiaNmt> {
         let mut nmt = CelestiaNmt::new();
-        for (idx, share) in self.shares.iter().enumerate() {
+        for (idx, share) in shares.iter().enumerate() {
             // Shares in the two left-hand quadrants are prefixed with their namespace, while parity
             // shares (in the right-hand) quadrants always have the PARITY_SHARES_NAMESPACE
-            let namespace = if idx < self.shares.len() / 2 {
+            let namespace = if idx < shares.len() / 2 {
                 share.namespace()
             } else {
                 PARITY_SHARES_NAMESPACE
             };
-            nmt.push_leaf(share.as_serialized(), namespace)
-                .expect("shares are pushed in order");
+            nmt.push_leaf(share.as_serialized(), namespace).ok()?;
         }
+        Some(nmt)
+    }
+
+    pub fn merklized(&self) -> CelestiaNmt {
+        let mut nmt = Self::tree_of(&self.shares).expect("shares are pushed in order");
         assert_eq!(&nmt.root(), &self.root);
         nmt
     }
@@ -178,62 +446,172 @@ pub struct RpcNamespacedSharesResponse(pub Option<Vec<RpcNamespacedShares>>);
 
 use nmt_rs::{
     simple_merkle::proof::Proof, CelestiaNmt, NamespaceProof, NamespacedHash, NamespacedSha2Hasher,
-    NAMESPACED_HASH_LEN,
 };
 
-impl Into<NamespaceProof<NamespacedSha2Hasher>> for JsonNamespaceProof {
-    fn into(self) -> NamespaceProof<NamespacedSha2Hasher> {
-        NamespaceProof::PresenceProof {
+impl TryFrom<JsonNamespaceProof> for NamespaceProof<NamespacedSha2Hasher> {
+    type Error = CidError;
+
+    fn try_from(value: JsonNamespaceProof) -> Result<Self, Self::Error> {
+        let siblings = value
+            .nodes
+            .unwrap_or_default()
+            .iter()
+            .map(|node| ns_hash_from_cid(&node.inner))
+            .collect::<Result<Vec<NamespacedHash>, _>>()?;
+        Ok(NamespaceProof::PresenceProof {
             proof: Proof {
-                siblings: self
-                    .nodes
-                    .unwrap_or_default()
-                    .into_iter()
-                    .map(|v| ns_hash_from_b64(&v.inner))
-                    .collect(),
-                start_idx: self.start as u32,
+                siblings,
+                start_idx: value.start as u32,
             },
             ignore_max_ns: true,
-        }
+        })
     }
 }
 
-fn ns_hash_from_b64(input: &str) -> NamespacedHash {
-    let mut output = [0u8; NAMESPACED_HASH_LEN];
-    base64::decode_config_slice(input, base64::STANDARD, &mut output[..])
-        .expect("must be valid b64");
-    NamespacedHash(output)
-}
-
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use nmt_rs::{NamespaceProof, NamespacedSha2Hasher};
+
+    use super::RpcNamespacedSharesResponse;
+    use super::ValidationError;
+    use crate::{
+        shares::{NamespaceGroup, Share},
+        test_utils::{single_blob_block, BlockBuilder, TestBlob, TEST_NAMESPACE},
+        DataAvailabilityHeader, MarshalledDataAvailabilityHeader,
+    };
+
+    const ROW_ROOTS: &[&'static str] = &[
+        "AAAAAAAAAAEAAAAAAAAAAT4A1HvHQCYkf1sQ7zmTJH11jd1Hxn+YCcC9mIGbl1WJ",
+        "c292LXRlc3T//////////vSMLQPlgfwCOf4QTkOhMnQxk6ra3lI+ybCMfUyanYSd",
+        "/////////////////////wp55V2JEu8z3LhdNIIqxbq6uvpyGSGu7prq67ajVVAt",
+        "/////////////////////7gaLStbqIBiy2pxi1D68MFUpq6sVxWBB4zdQHWHP/Tl",
+    ];
+
+    #[test]
+    fn test_known_good_msg() {
+        let msg = r#"[{"Proof":{"End":1,"Nodes":[{"/":"bagao4amb5yatb7777777777773777777777777tjxe2jqsatxobgu3jqwkwsefsxscursxyaqzvvrxzv73aphwunua"},{"/":"bagao4amb5yatb77777777777777777777777776yvm54zu2vfqwyhd2nsebctxar7pxutz6uya7z3m2tzsmdtshjbm"}],"Start":0},"Shares":["c292LXRlc3QBKHsia2V5IjogInRlc3RrZXkiLCAidmFsdWUiOiAidGVzdHZhbHVlIn0AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]}]"#;
+        let deserialized: RpcNamespacedSharesResponse =
+            serde_json::from_str(msg).expect("message must deserialize");
+        let roots = ROW_ROOTS.iter().map(|root| root.to_string()).collect();
+        let dah: DataAvailabilityHeader = MarshalledDataAvailabilityHeader {
+            row_roots: roots,
+            column_roots: vec![],
+        }
+        .try_into()
+        .unwrap();
+
+        for row in deserialized.0.expect("shares response is not empty") {
+            let proof: NamespaceProof<NamespacedSha2Hasher> = row
+                .proof
+                .try_into()
+                .expect("proof nodes must be valid cids");
+            let leaves: Vec<&[u8]> = row.shares.iter().map(Share::as_serialized).collect();
+            proof
+                .verify_complete_namespace(&dah.row_roots[1], &leaves, TEST_NAMESPACE)
+                .expect("proof should be valid");
+        }
+    }
+
+    #[test]
+    fn test_dah_structure() {
+        let (block, _) = single_blob_block(4, vec![1u8; 1000]);
+        let valid = block.header.dah;
+        assert_eq!(valid.validate_structure(), Ok(()));
+        let check = |modify: &dyn Fn(&mut DataAvailabilityHeader)| {
+            let mut dah = valid.clone();
+            modify(&mut dah);
+            dah.validate_structure()
+        };
+
+        assert_eq!(
+            check(&|dah| {
+                dah.column_roots.pop();
+            }),
+            Err(ValidationError::MismatchedRootCount)
+        );
+        assert_eq!(
+            check(&|dah| {
+                dah.row_roots.truncate(6);
+                dah.column_roots.truncate(6);
+            }),
+            Err(ValidationError::InvalidSquareSize)
+        );
+        assert_eq!(
+            check(&|dah| {
+                dah.row_roots.clear();
+                dah.column_roots.clear();
+            }),
+            Err(ValidationError::InvalidSquareSize)
+        );
+        assert_eq!(
+            check(&|dah| dah.row_roots[0].0[..8].copy_from_slice(&[0xfe; 8])),
+            Err(ValidationError::InvalidNamespaceRange)
+        );
+        // An original row swapped with a parity row
+        assert_eq!(
+            check(&|dah| dah.row_roots.swap(0, 4)),
+            Err(ValidationError::MisplacedParityRoot)
+        );
+        assert_eq!(
+            check(&|dah| dah.column_roots.swap(3, 4)),
+            Err(ValidationError::MisplacedParityRoot)
+        );
+        // The first row, which starts with the pfb namespace, swapped with the last row of tail padding
+        assert_eq!(
+            check(&|dah| dah.row_roots.swap(0, 3)),
+            Err(ValidationError::UnsortedRowRoots)
+        );
+    }
 
-    // use nmt_rs::{NamespaceProof, NamespacedSha2Hasher};
-
-    // use super::{ns_hash_from_b64, RpcNamespacedSharesResponse};
-
-    // const ROW_ROOTS: &[&'static str] = &[
-    //     "AAAAAAAAAAEAAAAAAAAAAT4A1HvHQCYkf1sQ7zmTJH11jd1Hxn+YCcC9mIGbl1WJ",
-    //     "c292LXRlc3T//////////vSMLQPlgfwCOf4QTkOhMnQxk6ra3lI+ybCMfUyanYSd",
-    //     "/////////////////////wp55V2JEu8z3LhdNIIqxbq6uvpyGSGu7prq67ajVVAt",
-    //     "/////////////////////7gaLStbqIBiy2pxi1D68MFUpq6sVxWBB4zdQHWHP/Tl",
-    // ];
-
-    // TODO: Re-enable this test after Celestia releases an endpoint which returns nmt proofs instead of
-    // ipld.Proofs
-    // #[test]
-    // fn test_known_good_msg() {
-    // let msg = r#"[{"Proof":{"End":1,"Nodes":[{"/":"bagao4amb5yatb7777777777773777777777777tjxe2jqsatxobgu3jqwkwsefsxscursxyaqzvvrxzv73aphwunua"},{"/":"bagao4amb5yatb77777777777777777777777776yvm54zu2vfqwyhd2nsebctxar7pxutz6uya7z3m2tzsmdtshjbm"}],"Start":0},"Shares":["c292LXRlc3QBKHsia2V5IjogInRlc3RrZXkiLCAidmFsdWUiOiAidGVzdHZhbHVlIn0AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]}]"#;
-    //     let deserialized: RpcNamespacedSharesResponse =
-    //         serde_json::from_str(msg).expect("message must deserialize");
-
-    //     let root = ns_hash_from_b64(ROW_ROOTS[0]);
-
-    //     for row in deserialized.0.expect("shares response is not empty") {
-    //         let proof: NamespaceProof<NamespacedSha2Hasher> = row.proof.into();
-    //         proof
-    //             .verify_range(&root, &row.shares, ROLLUP_NAMESPACE)
-    //             .expect("proof should be valid");
-    //     }
-    // }
+    #[test]
+    fn test_row_trees_are_shared_by_clones_and_ignored_by_eq() {
+        fn assert_sync<T: Sync>(_: &T) {}
+        let (block, _) = single_blob_block(4, vec![1u8; 1000]);
+        assert_sync(&block);
+        let unmerklized = block.clone();
+        block.merklize_rows();
+
+        // Clones taken after the trees were built share them, while earlier clones don't have them
+        let trees = block.row_trees.0.get().expect("trees were built");
+        let merklized = block.clone();
+        assert!(Arc::ptr_eq(trees, merklized.row_trees.0.get().unwrap()));
+        assert!(unmerklized.row_trees.0.get().is_none());
+
+        // Equality only depends on the data of the block
+        assert_eq!(block, merklized);
+        assert_eq!(block, unmerklized);
+        let mut modified = merklized.clone();
+        modified.pfb_rows.clear();
+        assert_ne!(block, modified);
+
+        let row = &block.rollup_data[0].rows[0];
+        assert_eq!(
+            block.with_row_tree(row, |nmt| nmt.root()),
+            unmerklized.with_row_tree(row, |nmt| nmt.root())
+        );
+    }
+
+    #[test]
+    fn test_check_blob_pfbs() {
+        let (block, _) = BlockBuilder::new(8)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![1u8; 600]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![2u8; 100]))
+            .build(TEST_NAMESPACE);
+        block.check_blob_pfbs().unwrap();
+
+        // Pfbs which are out of order attribute the blobs to the wrong senders
+        let mut reordered = block.clone();
+        reordered.relevant_pfbs.reverse();
+        assert!(reordered.check_blob_pfbs().is_err());
+
+        // A blob without a pfb, and a pfb without a blob
+        let mut missing_pfb = block.clone();
+        missing_pfb.relevant_pfbs.pop();
+        assert!(missing_pfb.check_blob_pfbs().is_err());
+        let mut missing_blob = block;
+        missing_blob.rollup_data[0].data = NamespaceGroup::Sparse(vec![]);
+        assert!(missing_blob.check_blob_pfbs().is_err());
+    }
 }
@@ -0,0 +1,526 @@
+//! A compact binary wire format for Jupiter's proofs.
+//!
+//! The borsh and serde encodings of [`EtxProof`] and [`RelevantRowProof`] length-prefix every share
+//! and repeat the namespace of every leaf, even though all of the leaves in a range proof share a
+//! namespace. Since proofs are passed to the zk guest as input, every one of those bytes has a cost.
+//! This format instead uses:
+//! - fixed size (512-byte) shares with no length prefix, and the namespace prefix written once per proof.
+//!   Shares of any other size can never verify, but are length-prefixed so that they still round-trip.
+//! - NMT nodes whose min and max namespace are equal written with a single copy of the namespace
+//! - LEB128 varints for all indices and lengths
+use std::fmt::Display;
+
+use nmt_rs::{
+    simple_merkle::proof::Proof, NamespaceId, NamespaceProof, NamespacedHash, NamespacedSha2Hasher,
+};
+use prost::encoding::{decode_varint, encode_varint, encoded_len_varint};
+
+use super::proofs::{EtxLocation, EtxProof, EtxRangeProof, RelevantRowProof};
+
+const SHARE_SIZE: usize = 512;
+const NAMESPACE_LEN: usize = 8;
+const HASH_LEN: usize = 32;
+const NAMESPACED_HASH_LEN: usize = 2 * NAMESPACE_LEN + HASH_LEN;
+
+const SHARES_SHARED_NAMESPACE: u8 = 0;
+const SHARES_RAW: u8 = 1;
+const SHARES_LENGTH_PREFIXED: u8 = 2;
+
+const NODE_SINGLE_NAMESPACE: u8 = 0;
+const NODE_NAMESPACE_RANGE: u8 = 1;
+
+const PROOF_FLAG_ABSENCE: u8 = 0b001;
+const PROOF_FLAG_IGNORE_MAX_NS: u8 = 0b010;
+const PROOF_FLAG_HAS_LEAF: u8 = 0b100;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompactDecodeError {
+    UnexpectedEof,
+    InvalidVarint,
+    InvalidTag(u8),
+    TrailingBytes,
+}
+
+impl Display for CompactDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompactDecodeError::UnexpectedEof => f.write_str("CompactDecodeError::UnexpectedEof"),
+            CompactDecodeError::InvalidVarint => f.write_str("CompactDecodeError::InvalidVarint"),
+            CompactDecodeError::InvalidTag(tag) => {
+                write!(f, "CompactDecodeError::InvalidTag({})", tag)
+            }
+            CompactDecodeError::TrailingBytes => f.write_str("CompactDecodeError::TrailingBytes"),
+        }
+    }
+}
+
+impl std::error::Error for CompactDecodeError {}
+
+/// A type which can be written in Jupiter's compact proof format
+pub trait CompactEncode: Sized {
+    /// Append the compact encoding of `self` to `out`
+    fn encode_compact(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the front of `buf`, advancing it past the bytes which were read
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError>;
+
+    /// The length of the compact encoding of `self`, computed without encoding it
+    fn compact_len(&self) -> usize;
+
+    fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_compact(&mut out);
+        out
+    }
+
+    /// Decode a value, requiring that `bytes` contains nothing else
+    fn from_compact_bytes(mut bytes: &[u8]) -> Result<Self, CompactDecodeError> {
+        let value = Self::decode_compact(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(CompactDecodeError::TrailingBytes);
+        }
+        Ok(value)
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], CompactDecodeError> {
+    if buf.len() < len {
+        return Err(CompactDecodeError::UnexpectedEof);
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_u8(buf: &mut &[u8]) -> Result<u8, CompactDecodeError> {
+    Ok(take(buf, 1)?[0])
+}
+
+fn take_usize(buf: &mut &[u8]) -> Result<usize, CompactDecodeError> {
+    let value = decode_varint(buf).map_err(|_| CompactDecodeError::InvalidVarint)?;
+    usize::try_from(value).map_err(|_| CompactDecodeError::InvalidVarint)
+}
+
+/// Bound an untrusted element count by the number of bytes left to read, so that a malicious length
+/// prefix can't trigger a huge allocation.
+fn capacity_hint(len: usize, buf: &[u8], min_element_size: usize) -> usize {
+    std::cmp::min(len, buf.len() / min_element_size)
+}
+
+impl CompactEncode for NamespacedHash {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        let (min_ns, rest) = self.0.split_at(NAMESPACE_LEN);
+        let (max_ns, hash) = rest.split_at(NAMESPACE_LEN);
+        if min_ns == max_ns {
+            out.push(NODE_SINGLE_NAMESPACE);
+            out.extend_from_slice(min_ns);
+        } else {
+            out.push(NODE_NAMESPACE_RANGE);
+            out.extend_from_slice(min_ns);
+            out.extend_from_slice(max_ns);
+        }
+        out.extend_from_slice(hash);
+    }
+
+    fn compact_len(&self) -> usize {
+        if self.0[..NAMESPACE_LEN] == self.0[NAMESPACE_LEN..2 * NAMESPACE_LEN] {
+            1 + NAMESPACE_LEN + HASH_LEN
+        } else {
+            1 + 2 * NAMESPACE_LEN + HASH_LEN
+        }
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        let mut out = [0u8; NAMESPACED_HASH_LEN];
+        match take_u8(buf)? {
+            NODE_SINGLE_NAMESPACE => {
+                let ns = take(buf, NAMESPACE_LEN)?;
+                out[..NAMESPACE_LEN].copy_from_slice(ns);
+                out[NAMESPACE_LEN..2 * NAMESPACE_LEN].copy_from_slice(ns);
+            }
+            NODE_NAMESPACE_RANGE => {
+                out[..2 * NAMESPACE_LEN].copy_from_slice(take(buf, 2 * NAMESPACE_LEN)?);
+            }
+            tag => return Err(CompactDecodeError::InvalidTag(tag)),
+        }
+        out[2 * NAMESPACE_LEN..].copy_from_slice(take(buf, HASH_LEN)?);
+        Ok(NamespacedHash(out))
+    }
+}
+
+impl CompactEncode for NamespaceProof<NamespacedSha2Hasher> {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        let (proof, ignore_max_ns, leaf, mut flags) = match self {
+            NamespaceProof::AbsenceProof {
+                proof,
+                ignore_max_ns,
+                leaf,
+            } => (proof, *ignore_max_ns, leaf.as_ref(), PROOF_FLAG_ABSENCE),
+            NamespaceProof::PresenceProof {
+                proof,
+                ignore_max_ns,
+            } => (proof, *ignore_max_ns, None, 0),
+        };
+        if ignore_max_ns {
+            flags |= PROOF_FLAG_IGNORE_MAX_NS;
+        }
+        if leaf.is_some() {
+            flags |= PROOF_FLAG_HAS_LEAF;
+        }
+        out.push(flags);
+        encode_varint(proof.start_idx as u64, out);
+        encode_varint(proof.siblings.len() as u64, out);
+        for sibling in proof.siblings.iter() {
+            sibling.encode_compact(out);
+        }
+        if let Some(leaf) = leaf {
+            leaf.encode_compact(out);
+        }
+    }
+
+    fn compact_len(&self) -> usize {
+        let (proof, leaf) = match self {
+            NamespaceProof::AbsenceProof { proof, leaf, .. } => (proof, leaf.as_ref()),
+            NamespaceProof::PresenceProof { proof, .. } => (proof, None),
+        };
+        1 + encoded_len_varint(proof.start_idx as u64)
+            + encoded_len_varint(proof.siblings.len() as u64)
+            + proof
+                .siblings
+                .iter()
+                .map(CompactEncode::compact_len)
+                .sum::<usize>()
+            + leaf.map_or(0, CompactEncode::compact_len)
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        let flags = take_u8(buf)?;
+        if flags & !(PROOF_FLAG_ABSENCE | PROOF_FLAG_IGNORE_MAX_NS | PROOF_FLAG_HAS_LEAF) != 0 {
+            return Err(CompactDecodeError::InvalidTag(flags));
+        }
+        let start_idx =
+            u32::try_from(take_usize(buf)?).map_err(|_| CompactDecodeError::InvalidVarint)?;
+        let num_siblings = take_usize(buf)?;
+        let mut siblings = Vec::with_capacity(capacity_hint(
+            num_siblings,
+            buf,
+            1 + NAMESPACE_LEN + HASH_LEN,
+        ));
+        for _ in 0..num_siblings {
+            siblings.push(NamespacedHash::decode_compact(buf)?);
+        }
+        let proof = Proof {
+            siblings,
+            start_idx,
+        };
+        let ignore_max_ns = flags & PROOF_FLAG_IGNORE_MAX_NS != 0;
+
+        if flags & PROOF_FLAG_ABSENCE != 0 {
+            let leaf = if flags & PROOF_FLAG_HAS_LEAF != 0 {
+                Some(NamespacedHash::decode_compact(buf)?)
+            } else {
+                None
+            };
+            Ok(NamespaceProof::AbsenceProof {
+                proof,
+                ignore_max_ns,
+                leaf,
+            })
+        } else {
+            if flags & PROOF_FLAG_HAS_LEAF != 0 {
+                return Err(CompactDecodeError::InvalidTag(flags));
+            }
+            Ok(NamespaceProof::PresenceProof {
+                proof,
+                ignore_max_ns,
+            })
+        }
+    }
+}
+
+/// Encode a list of shares. If every share has the same namespace (as is always the case for the leaves of a
+/// namespace or range proof), the namespace is written only once.
+fn encode_shares(shares: &[Vec<u8>], out: &mut Vec<u8>) {
+    encode_varint(shares.len() as u64, out);
+    let Some(first) = shares.first() else {
+        return;
+    };
+    let tag = shares_tag(shares);
+    out.push(tag);
+    match tag {
+        SHARES_SHARED_NAMESPACE => {
+            out.extend_from_slice(&first[..NAMESPACE_LEN]);
+            for share in shares {
+                out.extend_from_slice(&share[NAMESPACE_LEN..]);
+            }
+        }
+        SHARES_RAW => {
+            for share in shares {
+                out.extend_from_slice(share);
+            }
+        }
+        _ => {
+            for share in shares {
+                encode_varint(share.len() as u64, out);
+                out.extend_from_slice(share);
+            }
+        }
+    }
+}
+
+fn shares_len(shares: &[Vec<u8>]) -> usize {
+    let prefix_len = encoded_len_varint(shares.len() as u64);
+    if shares.is_empty() {
+        return prefix_len;
+    }
+    let body_len = match shares_tag(shares) {
+        SHARES_SHARED_NAMESPACE => NAMESPACE_LEN + shares.len() * (SHARE_SIZE - NAMESPACE_LEN),
+        SHARES_RAW => shares.len() * SHARE_SIZE,
+        _ => shares
+            .iter()
+            .map(|share| encoded_len_varint(share.len() as u64) + share.len())
+            .sum(),
+    };
+    prefix_len + 1 + body_len
+}
+
+/// Picks the layout of a non-empty list of shares
+fn shares_tag(shares: &[Vec<u8>]) -> u8 {
+    if !shares.iter().all(|share| share.len() == SHARE_SIZE) {
+        return SHARES_LENGTH_PREFIXED;
+    }
+    let namespace = &shares[0][..NAMESPACE_LEN];
+    if shares
+        .iter()
+        .all(|share| &share[..NAMESPACE_LEN] == namespace)
+    {
+        SHARES_SHARED_NAMESPACE
+    } else {
+        SHARES_RAW
+    }
+}
+
+fn decode_shares(buf: &mut &[u8]) -> Result<Vec<Vec<u8>>, CompactDecodeError> {
+    let num_shares = take_usize(buf)?;
+    if num_shares == 0 {
+        return Ok(vec![]);
+    }
+    let tag = take_u8(buf)?;
+    let min_share_size = match tag {
+        SHARES_SHARED_NAMESPACE => SHARE_SIZE - NAMESPACE_LEN,
+        SHARES_RAW => SHARE_SIZE,
+        // Every share has at least a one byte length prefix
+        SHARES_LENGTH_PREFIXED => 1,
+        tag => return Err(CompactDecodeError::InvalidTag(tag)),
+    };
+    let mut shares = Vec::with_capacity(capacity_hint(num_shares, buf, min_share_size));
+    match tag {
+        SHARES_SHARED_NAMESPACE => {
+            let namespace = take(buf, NAMESPACE_LEN)?;
+            for _ in 0..num_shares {
+                let mut share = Vec::with_capacity(SHARE_SIZE);
+                share.extend_from_slice(namespace);
+                share.extend_from_slice(take(buf, SHARE_SIZE - NAMESPACE_LEN)?);
+                shares.push(share);
+            }
+        }
+        SHARES_RAW => {
+            for _ in 0..num_shares {
+                shares.push(take(buf, SHARE_SIZE)?.to_vec());
+            }
+        }
+        _ => {
+            for _ in 0..num_shares {
+                let len = take_usize(buf)?;
+                shares.push(take(buf, len)?.to_vec());
+            }
+        }
+    }
+    Ok(shares)
+}
+
+impl CompactEncode for EtxRangeProof {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        encode_varint(self.start_share_idx as u64, out);
+        encode_shares(&self.shares, out);
+        self.proof.encode_compact(out);
+    }
+
+    fn compact_len(&self) -> usize {
+        encoded_len_varint(self.start_share_idx as u64)
+            + shares_len(&self.shares)
+            + self.proof.compact_len()
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        let start_share_idx = take_usize(buf)?;
+        let shares = decode_shares(buf)?;
+        let proof = NamespaceProof::decode_compact(buf)?;
+        Ok(Self {
+            shares,
+            proof,
+            start_share_idx,
+        })
+    }
+}
+
+impl CompactEncode for EtxLocation {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        encode_varint(self.start_share_idx as u64, out);
+        encode_varint(self.start_offset as u64, out);
+        encode_varint(self.blob_idx as u64, out);
+    }
+
+    fn compact_len(&self) -> usize {
+        encoded_len_varint(self.start_share_idx as u64)
+            + encoded_len_varint(self.start_offset as u64)
+            + encoded_len_varint(self.blob_idx as u64)
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        Ok(Self {
+            start_share_idx: take_usize(buf)?,
+            start_offset: take_usize(buf)?,
+            blob_idx: take_usize(buf)?,
+        })
+    }
+}
+
+impl CompactEncode for EtxProof {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        self.ranges.encode_compact(out);
+        self.txs.encode_compact(out);
+    }
+
+    fn compact_len(&self) -> usize {
+        self.ranges.compact_len() + self.txs.compact_len()
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        Ok(Self {
+            ranges: Vec::decode_compact(buf)?,
+            txs: Vec::decode_compact(buf)?,
+        })
+    }
+}
+
+impl CompactEncode for RelevantRowProof {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.namespace.0);
+        encode_shares(&self.leaves, out);
+        self.proof.encode_compact(out);
+    }
+
+    fn compact_len(&self) -> usize {
+        NAMESPACE_LEN + shares_len(&self.leaves) + self.proof.compact_len()
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        let namespace = NamespaceId(
+            take(buf, NAMESPACE_LEN)?
+                .try_into()
+                .expect("slice has the length of a namespace"),
+        );
+        let leaves = decode_shares(buf)?;
+        let proof = NamespaceProof::decode_compact(buf)?;
+        Ok(Self {
+            namespace,
+            leaves,
+            proof,
+        })
+    }
+}
+
+impl<T: CompactEncode> CompactEncode for Vec<T> {
+    fn encode_compact(&self, out: &mut Vec<u8>) {
+        encode_varint(self.len() as u64, out);
+        for item in self.iter() {
+            item.encode_compact(out);
+        }
+    }
+
+    fn compact_len(&self) -> usize {
+        encoded_len_varint(self.len() as u64)
+            + self.iter().map(CompactEncode::compact_len).sum::<usize>()
+    }
+
+    fn decode_compact(buf: &mut &[u8]) -> Result<Self, CompactDecodeError> {
+        let len = take_usize(buf)?;
+        // Every item occupies at least one byte
+        let mut out = Vec::with_capacity(capacity_hint(len, buf, 1));
+        for _ in 0..len {
+            out.push(T::decode_compact(buf)?);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use borsh::BorshSerialize;
+
+    use super::CompactEncode;
+    use crate::{
+        test_utils::{single_blob_block, BlockBuilder, TestBlob, TEST_NAMESPACE},
+        verifier::proofs::{
+            CompletenessProof, CorrectnessProof, EtxProof, EtxRangeProof, RelevantRowProof,
+        },
+    };
+
+    #[test]
+    fn test_compact_proofs_roundtrip() {
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![7u8; 1500]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![9u8; 100]))
+            .build(TEST_NAMESPACE);
+
+        let etx_proofs = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        let row_proofs = CompletenessProof::from_filtered_block(&block).0;
+
+        let encoded_etx = etx_proofs.to_compact_bytes();
+        let encoded_rows = row_proofs.to_compact_bytes();
+        assert_eq!(
+            EtxProof::from_compact_bytes(&encoded_etx).unwrap(),
+            etx_proofs
+        );
+        assert_eq!(
+            Vec::<RelevantRowProof>::from_compact_bytes(&encoded_rows).unwrap(),
+            row_proofs
+        );
+
+        assert_eq!(etx_proofs.compact_len(), encoded_etx.len());
+        assert_eq!(row_proofs.compact_len(), encoded_rows.len());
+        assert!(encoded_etx.len() < etx_proofs.try_to_vec().unwrap().len());
+        assert!(encoded_rows.len() < row_proofs.try_to_vec().unwrap().len());
+    }
+
+    #[test]
+    fn test_compact_decode_rejects_truncated_input() {
+        let (block, _) = single_blob_block(2, vec![1u8; 600]);
+        let encoded = CompletenessProof::from_filtered_block(&block)
+            .0
+            .to_compact_bytes();
+        for len in 0..encoded.len() {
+            assert!(Vec::<RelevantRowProof>::from_compact_bytes(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_compact_shares_of_the_wrong_size_roundtrip() {
+        let (block, txs) = single_blob_block(4, vec![1u8; 100]);
+        let range = CorrectnessProof::for_block(&block, &txs).unwrap().0.ranges[0].clone();
+        for shares in [
+            vec![vec![1u8; 100]],
+            vec![range.shares[0].clone(), vec![2u8; 600]],
+            vec![vec![], range.shares[0].clone()],
+        ] {
+            let range = EtxRangeProof {
+                shares,
+                ..range.clone()
+            };
+            let encoded = range.to_compact_bytes();
+            assert_eq!(range.compact_len(), encoded.len());
+            assert_eq!(EtxRangeProof::from_compact_bytes(&encoded).unwrap(), range);
+        }
+    }
+}
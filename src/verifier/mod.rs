@@ -1,30 +1,32 @@
-use nmt_rs::NamespaceId;
+use std::collections::{BTreeMap, BTreeSet};
+
+use nmt_rs::{NamespaceId, NamespaceProof, NamespacedSha2Hasher};
 use serde::{Deserialize, Serialize};
-use sovereign_sdk::{
-    da::{self, BlobTransactionTrait, BlockHashTrait as BlockHash, DaSpec},
-    Bytes,
-};
+use sovereign_sdk::da::{self, BlobTransactionTrait, BlockHashTrait as BlockHash, DaSpec};
 
 pub mod address;
+pub mod compact;
 pub mod proofs;
 
 use crate::{
     pfb_from_iter,
     share_commit::recreate_commitment,
-    shares::{read_varint, BlobIterator, NamespaceGroup, Share},
+    shares::{bufs_eq, read_varint, BlobIterator, BlobRef, NamespaceGroup, Share},
     types::ValidationError,
-    BlobWithSender, CelestiaHeader, DataAvailabilityHeader,
+    BlobMetadata, BlobWithSender, CelestiaHeader, DataAvailabilityHeader,
 };
 use proofs::*;
 
 use self::address::CelestiaAddress;
 
 pub struct CelestiaVerifier {
-    pub rollup_namespace: NamespaceId,
+    pub rollup_namespaces: BTreeSet<NamespaceId>,
 }
 
 pub const PFB_NAMESPACE: NamespaceId = NamespaceId(hex_literal::hex!("0000000000000004"));
 pub const PARITY_SHARES_NAMESPACE: NamespaceId = NamespaceId(hex_literal::hex!("ffffffffffffffff"));
+/// The largest width of the original data square permitted by celestia-app
+pub const MAX_ODS_WIDTH: usize = 128;
 
 impl BlobTransactionTrait for BlobWithSender {
     type Data = BlobIterator;
@@ -33,6 +35,7 @@ impl BlobTransactionTrait for BlobWithSender {
         self.sender.clone()
     }
 
+    // Cloning a blob only clones the (reference counted) handles to its shares, not their data
     fn data(&self) -> Self::Data {
         self.blob.clone().into_iter()
     }
@@ -79,7 +82,7 @@ impl DaSpec for CelestiaSpec {
 
     type BlobTransaction = BlobWithSender;
 
-    type InclusionMultiProof = Vec<EtxProof>;
+    type InclusionMultiProof = EtxProof;
 
     type CompletenessProof = Vec<RelevantRowProof>;
 
@@ -88,7 +91,8 @@ impl DaSpec for CelestiaSpec {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RollupParams {
-    pub namespace: NamespaceId,
+    /// The namespaces from which the rollup reads blobs
+    pub namespaces: BTreeSet<NamespaceId>,
 }
 
 impl da::DaVerifier for CelestiaVerifier {
@@ -98,7 +102,7 @@ impl da::DaVerifier for CelestiaVerifier {
 
     fn new(params: <Self::Spec as DaSpec>::ChainParams) -> Self {
         Self {
-            rollup_namespace: params.namespace,
+            rollup_namespaces: params.namespaces,
         }
     }
 
@@ -112,95 +116,121 @@ impl da::DaVerifier for CelestiaVerifier {
         // Validate that the provided DAH is well-formed
         block_header.validate_dah()?;
 
-        // Check the validity and completeness of the rollup row proofs, against the DAH.
+        // Check the validity and completeness of the row proofs of each rollup namespace, against the DAH.
         // Extract the data from the row proofs and build a namespace_group from it
-        let rollup_shares_u8 = self.verify_row_proofs(completeness_proof, &block_header.dah)?;
-        if rollup_shares_u8.is_empty() {
-            if txs.is_empty() {
-                return Ok(());
+        let mut row_proofs = completeness_proof.into_iter().peekable();
+        let mut namespaces = Vec::with_capacity(self.rollup_namespaces.len());
+        for &rollup_namespace in self.rollup_namespaces.iter() {
+            let mut namespace_proofs = Vec::new();
+            while let Some(row_proof) =
+                row_proofs.next_if(|proof| proof.namespace == rollup_namespace)
+            {
+                namespace_proofs.push(row_proof);
             }
-            return Err(ValidationError::MissingTx);
+            let (namespace_start, rollup_shares_u8) =
+                self.verify_row_proofs(rollup_namespace, namespace_proofs, &block_header.dah)?;
+            if let Some(namespace_start) = namespace_start {
+                let namespace = NamespaceGroup::from_shares(rollup_shares_u8)
+                    .map_err(|_| ValidationError::InvalidRowProof)?;
+                namespaces.push((rollup_namespace, namespace_start, namespace));
+            }
+        }
+        // Reject proofs for namespaces which the rollup doesn't read
+        if row_proofs.next().is_some() {
+            return Err(ValidationError::InvalidRowProof);
         }
-        let namespace = NamespaceGroup::from_shares_unchecked(rollup_shares_u8);
 
         // Check the e-tx proofs...
         // TODO(@preston-evans98): Remove this logic if Celestia adds blob.sender metadata directly into blob
-        let mut tx_iter = txs.iter();
         let square_size = block_header.dah.row_roots.len();
-        for (blob, tx_proof) in namespace.blobs().zip(inclusion_proof.into_iter()) {
-            // Force the row number to be monotonically increasing
-            let start_offset = tx_proof.proof[0].start_offset;
-
-            // Verify each sub-proof and flatten the shares back into a sequential array
-            // First, enforce that the sub-proofs cover a contiguous range of shares
-            for [l, r] in tx_proof.proof.array_windows::<2>() {
-                assert_eq!(l.start_share_idx + l.shares.len(), r.start_share_idx)
-            }
-            let mut tx_shares = Vec::new();
-            // Then, verify the sub proofs
-            for sub_proof in tx_proof.proof.into_iter() {
-                let row_num = sub_proof.start_share_idx / square_size;
-                let root = &block_header.dah.row_roots[row_num];
-                sub_proof
-                    .proof
-                    .verify_range(root, &sub_proof.shares, PFB_NAMESPACE)
-                    .map_err(|_| ValidationError::InvalidEtxProof("invalid sub proof"))?;
-                tx_shares.extend(
-                    sub_proof
-                        .shares
-                        .into_iter()
-                        .map(|share_vec| Share::new(share_vec.into())),
-                )
-            }
-
-            // Next, ensure that the start_index is valid
-            if !tx_shares[0].is_valid_tx_start(start_offset) {
-                return Err(ValidationError::InvalidEtxProof("invalid start index"));
-            }
-
-            // Collect all of the shares data into a single array
-            let trailing_shares = tx_shares[1..]
-                .iter()
-                .map(|share| share.data_ref().iter())
-                .flatten();
-            let tx_data: Vec<u8> = tx_shares[0].data_ref()[start_offset..]
-                .iter()
-                .chain(trailing_shares)
-                .map(|x| *x)
-                .collect();
-
-            // Deserialize the pfb transaction
-            let (len, len_of_len) = {
-                let cursor = std::io::Cursor::new(&tx_data);
-                read_varint(cursor).expect("tx must be length prefixed")
-            };
-            let mut cursor = std::io::Cursor::new(&tx_data[len_of_len..len as usize + len_of_len]);
-
-            let pfb = pfb_from_iter(&mut cursor, len as usize)
-                .map_err(|_| ValidationError::InvalidEtxProof("invalid pfb"))?;
-
-            // Verify the sender and data of each blob which was sent into this namespace
-            for (blob_idx, nid) in pfb.namespace_ids.iter().enumerate() {
-                if nid != &self.rollup_namespace.0[..] {
-                    continue;
+        let num_blobs: usize = namespaces
+            .iter()
+            .map(|(_, _, namespace)| namespace.blobs().count())
+            .sum();
+        if txs.len() != num_blobs || inclusion_proof.txs.len() != num_blobs {
+            return Err(ValidationError::MissingTx);
+        }
+        if num_blobs == 0 {
+            return Ok(());
+        }
+        let etx_shares = verify_etx_ranges(inclusion_proof.ranges, &block_header.dah)?;
+        let mut locations = inclusion_proof.txs.into_iter();
+        let mut txs = txs.iter();
+        for (rollup_namespace, namespace_start, namespace) in namespaces {
+            // Blobs are stored in the same order as the pfbs which pay for them. Requiring the pfb locations to be
+            // strictly increasing within each namespace ensures that each blob is attributed to a distinct pfb, so
+            // duplicate blobs can't be credited to the wrong sender.
+            let mut previous_location = None;
+            let mut share_offset = 0;
+            for blob in namespace.blobs() {
+                let location = locations.next().ok_or(ValidationError::MissingTx)?;
+                let tx = txs.next().ok_or(ValidationError::MissingTx)?;
+                let current_location = (
+                    location.start_share_idx,
+                    location.start_offset,
+                    location.blob_idx,
+                );
+                if previous_location.map_or(false, |previous| previous >= current_location) {
+                    return Err(ValidationError::InvalidEtxProof("pfbs out of order"));
+                }
+                previous_location = Some(current_location);
+
+                let tx_data = read_etx(&etx_shares, &location, square_size)?;
+
+                // Deserialize the pfb transaction
+                let (len, len_of_len) = read_varint(&tx_data[..])
+                    .map_err(|_| ValidationError::InvalidEtxProof("invalid length prefix"))?;
+                let mut cursor = std::io::Cursor::new(&tx_data[len_of_len..]);
+
+                let (pfb, pfb_tx_hash) = pfb_from_iter(&mut cursor, len as usize)
+                    .map_err(|_| ValidationError::InvalidEtxProof("invalid pfb"))?;
+
+                // Verify the sender and data of the blob
+                let nid = pfb
+                    .namespace_ids
+                    .get(location.blob_idx)
+                    .ok_or(ValidationError::InvalidEtxProof("invalid blob index"))?;
+                if nid != &rollup_namespace.0[..] {
+                    return Err(ValidationError::InvalidEtxProof(
+                        "blob index not in namespace",
+                    ));
                 }
-                let tx = tx_iter.next().ok_or(ValidationError::MissingTx)?;
                 if tx.sender.as_ref() != pfb.signer.as_bytes() {
                     return Err(ValidationError::InvalidSigner);
                 }
 
-                let blob_ref = blob.clone();
-                let blob_data: Bytes = blob.clone().data().collect();
-                let tx_data: Bytes = tx.data().collect();
-                assert_eq!(blob_data, tx_data);
+                let malformed = |_| ValidationError::InvalidEtxProof("malformed blob");
+                let blob_data = blob.try_data().map_err(malformed)?;
+                let tx_data = BlobRef::with(&tx.blob.0).try_data().map_err(malformed)?;
+                if !bufs_eq(blob_data, tx_data) {
+                    return Err(ValidationError::InvalidEtxProof("blob data mismatch"));
+                }
 
                 // Link blob commitment to e-tx commitment
-                let expected_commitment =
-                    recreate_commitment(square_size, blob_ref).map_err(|_| {
-                        ValidationError::InvalidEtxProof("failed to recreate commitment")
-                    })?;
+                let blob_len = blob.0.len();
+                let expected_commitment = recreate_commitment(square_size, blob).map_err(|_| {
+                    ValidationError::InvalidEtxProof("failed to recreate commitment")
+                })?;
+                let commitment = pfb
+                    .share_commitments
+                    .get(location.blob_idx)
+                    .ok_or(ValidationError::InvalidEtxProof("invalid blob index"))?;
+                if commitment[..] != expected_commitment[..] {
+                    return Err(ValidationError::InvalidEtxProof("commitment mismatch"));
+                }
 
-                assert_eq!(&pfb.share_commitments[blob_idx][..], &expected_commitment);
+                // Check that the metadata matches the blob's position and the pfb which paid for it
+                let expected_metadata = BlobMetadata::new(
+                    rollup_namespace,
+                    namespace_start + share_offset,
+                    square_size / 2,
+                    expected_commitment,
+                    pfb_tx_hash,
+                );
+                if tx.metadata != expected_metadata {
+                    return Err(ValidationError::InvalidBlobMetadata);
+                }
+                share_offset += blob_len;
             }
         }
 
@@ -209,28 +239,140 @@ impl da::DaVerifier for CelestiaVerifier {
 }
 
 impl CelestiaVerifier {
+    /// Verifies the row proofs of `namespace` against the DAH, returning the namespace's shares along with the
+    /// index (in the original data square) of the first one.
     pub fn verify_row_proofs(
         &self,
+        namespace: NamespaceId,
         row_proofs: Vec<RelevantRowProof>,
         dah: &DataAvailabilityHeader,
-    ) -> Result<Vec<Vec<u8>>, ValidationError> {
+    ) -> Result<(Option<usize>, Vec<Vec<u8>>), ValidationError> {
         let mut row_proofs = row_proofs.into_iter();
+        let ods_width = dah.row_roots.len() / 2;
+        // If the namespace is empty, require an explicit proof of its absence from the row where it would be
+        if let Some(row_idx) = dah.absence_row(namespace) {
+            let row_proof = row_proofs.next().ok_or(ValidationError::InvalidRowProof)?;
+            if !row_proof.leaves.is_empty() || row_proofs.next().is_some() {
+                return Err(ValidationError::InvalidRowProof);
+            }
+            row_proof
+                .proof
+                .verify_complete_namespace(&dah.row_roots[row_idx], &row_proof.leaves, namespace)
+                .map_err(|_| ValidationError::InvalidRowProof)?;
+            return Ok((None, Vec::new()));
+        }
+
         // Check the validity and completeness of the rollup share proofs
+        let mut namespace_start = None;
         let mut rollup_shares_u8: Vec<Vec<u8>> = Vec::new();
-        for row_root in dah.row_roots.iter() {
+        for (row_idx, row_root) in dah.row_roots.iter().enumerate() {
             // TODO: short circuit this loop at the first row after the rollup namespace
-            if row_root.contains(self.rollup_namespace) {
+            if row_root.contains(namespace) {
                 let row_proof = row_proofs.next().ok_or(ValidationError::InvalidRowProof)?;
                 row_proof
                     .proof
-                    .verify_complete_namespace(row_root, &row_proof.leaves, self.rollup_namespace)
-                    .expect("Proofs must be valid");
+                    .verify_complete_namespace(row_root, &row_proof.leaves, namespace)
+                    .map_err(|_| ValidationError::InvalidRowProof)?;
 
+                if namespace_start.is_none() && !row_proof.leaves.is_empty() {
+                    namespace_start =
+                        Some(row_idx * ods_width + proof_start_idx(&row_proof.proof) as usize);
+                }
                 for leaf in row_proof.leaves {
                     rollup_shares_u8.push(leaf)
                 }
             }
         }
-        Ok(rollup_shares_u8)
+        if row_proofs.next().is_some() {
+            return Err(ValidationError::InvalidRowProof);
+        }
+        Ok((namespace_start, rollup_shares_u8))
+    }
+}
+
+/// Verifies the range proofs of an [`EtxProof`] against the row roots, returning the proven shares keyed by
+/// their index in the extended data square.
+fn verify_etx_ranges(
+    ranges: Vec<EtxRangeProof>,
+    dah: &DataAvailabilityHeader,
+) -> Result<BTreeMap<usize, Share>, ValidationError> {
+    let square_size = dah.row_roots.len();
+    let mut etx_shares = BTreeMap::new();
+    for range in ranges {
+        let row_num = range.start_share_idx / square_size;
+        let start_column = range.start_share_idx % square_size;
+        // Pfbs are never stored in parity shares, so each range must fall inside the original data square
+        if start_column + range.shares.len() > square_size / 2 {
+            return Err(ValidationError::InvalidEtxProof("range exceeds row"));
+        }
+        if proof_start_idx(&range.proof) as usize != start_column {
+            return Err(ValidationError::InvalidEtxProof(
+                "range proof start mismatch",
+            ));
+        }
+        let root = dah
+            .row_roots
+            .get(row_num)
+            .ok_or(ValidationError::InvalidEtxProof("range exceeds square"))?;
+        range
+            .proof
+            .verify_range(root, &range.shares, PFB_NAMESPACE)
+            .map_err(|_| ValidationError::InvalidEtxProof("invalid sub proof"))?;
+
+        for (idx, share) in range.shares.into_iter().enumerate() {
+            let share = Share::try_new(share.into())
+                .map_err(|_| ValidationError::InvalidEtxProof("invalid share"))?;
+            if etx_shares
+                .insert(range.start_share_idx + idx, share)
+                .is_some()
+            {
+                return Err(ValidationError::InvalidEtxProof("duplicate share"));
+            }
+        }
+    }
+    Ok(etx_shares)
+}
+
+/// Reassembles a length-prefixed pfb from the proven shares, following it across rows if necessary.
+fn read_etx(
+    etx_shares: &BTreeMap<usize, Share>,
+    location: &EtxLocation,
+    square_size: usize,
+) -> Result<Vec<u8>, ValidationError> {
+    let mut share_idx = location.start_share_idx;
+    let first_share = etx_shares
+        .get(&share_idx)
+        .ok_or(ValidationError::InvalidEtxProof("missing share"))?;
+    // Ensure that the start_index is valid
+    if !first_share.is_valid_tx_start(location.start_offset) {
+        return Err(ValidationError::InvalidEtxProof("invalid start index"));
+    }
+
+    let mut tx_data = first_share.data_ref()[location.start_offset..].to_vec();
+    loop {
+        if let Ok((len, len_of_len)) = read_varint(&tx_data[..]) {
+            let total_len = (len as usize).saturating_add(len_of_len);
+            if tx_data.len() >= total_len {
+                tx_data.truncate(total_len);
+                return Ok(tx_data);
+            }
+        }
+        // The tx continues into the next share of the original data square
+        share_idx = if (share_idx % square_size) + 1 < square_size / 2 {
+            share_idx + 1
+        } else {
+            (share_idx / square_size + 1) * square_size
+        };
+        let share = etx_shares
+            .get(&share_idx)
+            .ok_or(ValidationError::InvalidEtxProof("missing share"))?;
+        tx_data.extend_from_slice(share.data_ref());
+    }
+}
+
+fn proof_start_idx(proof: &NamespaceProof<NamespacedSha2Hasher>) -> u32 {
+    match proof {
+        NamespaceProof::AbsenceProof { proof, .. } => proof.start_idx,
+        NamespaceProof::PresenceProof { proof, .. } => proof.start_idx,
     }
 }
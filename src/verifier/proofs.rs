@@ -1,27 +1,50 @@
+use std::{collections::BTreeSet, fmt::Display};
+
 use borsh::{BorshDeserialize, BorshSerialize};
 use nmt_rs::{NamespaceId, NamespaceProof, NamespacedSha2Hasher};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    share_commit::recreate_commitment, shares::BlobRef, types::FilteredCelestiaBlock,
+    share_commit::{recreate_commitment, CommitmentError},
+    shares::BlobRef,
+    telemetry,
+    types::FilteredCelestiaBlock,
     BlobWithSender,
 };
 
+/// A proof of all of the PFB shares needed to link a block's rollup blobs to their senders.
+/// Shares which contain (parts of) several relevant PFBs are included only once.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 pub struct EtxProof {
-    pub proof: Vec<EtxRangeProof>,
+    /// Range proofs for every needed share, sorted by share index. Consecutive needed shares in the
+    /// same row are proven together.
+    pub ranges: Vec<EtxRangeProof>,
+    /// The location of each relevant PFB within the proven shares, in the same order as the blobs
+    pub txs: Vec<EtxLocation>,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 pub struct EtxRangeProof {
     pub shares: Vec<Vec<u8>>,
     pub proof: NamespaceProof<NamespacedSha2Hasher>,
+    /// The index of the first share in the extended data square
+    pub start_share_idx: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+pub struct EtxLocation {
+    /// The index (in the extended data square) of the share in which the PFB starts
     pub start_share_idx: usize,
+    /// The offset into the data of the first share at which the PFB starts
     pub start_offset: usize,
+    /// The index of the blob within the PFB
+    pub blob_idx: usize,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 pub struct RelevantRowProof {
+    /// The rollup namespace whose completeness this row proves
+    pub namespace: NamespaceId,
     pub leaves: Vec<Vec<u8>>,
     pub proof: NamespaceProof<NamespacedSha2Hasher>,
 }
@@ -30,81 +53,431 @@ pub struct RelevantRowProof {
 pub struct CompletenessProof(pub Vec<RelevantRowProof>);
 
 impl CompletenessProof {
-    pub fn from_filtered_block(block: &FilteredCelestiaBlock, namespace: NamespaceId) -> Self {
+    /// Proves the complete contents of every rollup namespace of the block, sorted by namespace and then by row
+    pub fn from_filtered_block(block: &FilteredCelestiaBlock) -> Self {
+        block.merklize_rows();
         let mut row_proofs = Vec::new();
-        for row in block.rollup_rows.iter() {
-            let mut nmt = row.merklized();
-            let (leaves, proof) = nmt.get_namespace_with_proof(namespace);
-            let row_proof = RelevantRowProof { leaves, proof };
-            row_proofs.push(row_proof)
+        for namespace_data in block.rollup_data.iter() {
+            for row in namespace_data.rows.iter() {
+                let (leaves, proof) = block.with_row_tree(row, |nmt| {
+                    nmt.get_namespace_with_proof(namespace_data.namespace)
+                });
+                let row_proof = RelevantRowProof {
+                    namespace: namespace_data.namespace,
+                    leaves,
+                    proof,
+                };
+                row_proofs.push(row_proof)
+            }
         }
+        telemetry::record_proof_size("completeness", &row_proofs);
         Self(row_proofs)
     }
 }
 
-pub struct CorrectnessProof(pub Vec<EtxProof>);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProofGenerationError {
+    /// The blob is too large to have been included in the block
+    InvalidBlob(CommitmentError),
+    /// None of the block's unused relevant pfbs pays for a blob with this commitment from the tx's sender
+    MissingPfb([u8; 32]),
+    /// The block has relevant txs but no shares in the pfb namespace
+    MissingPfbNamespace,
+    /// The block has no pfb data in the row with this index
+    MissingRow(usize),
+}
+
+impl Display for ProofGenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofGenerationError::InvalidBlob(e) => {
+                write!(f, "ProofGenerationError::InvalidBlob({})", e)
+            }
+            ProofGenerationError::MissingPfb(commitment) => write!(
+                f,
+                "ProofGenerationError::MissingPfb(0x{})",
+                hex::encode(commitment)
+            ),
+            ProofGenerationError::MissingPfbNamespace => {
+                f.write_str("ProofGenerationError::MissingPfbNamespace")
+            }
+            ProofGenerationError::MissingRow(row_idx) => {
+                write!(f, "ProofGenerationError::MissingRow({})", row_idx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProofGenerationError {}
+
+pub struct CorrectnessProof(pub EtxProof);
 
 impl CorrectnessProof {
-    pub fn for_block(block: &FilteredCelestiaBlock, relevant_txs: &Vec<BlobWithSender>) -> Self {
-        let mut needed_tx_shares = Vec::new();
+    /// Builds a proof of the pfbs paying for `relevant_txs`. The transactions must be provided in the same order
+    /// as their blobs appear in the rollup namespaces, but their pfbs may appear in any order and may span any
+    /// number of rows.
+    pub fn for_block(
+        block: &FilteredCelestiaBlock,
+        relevant_txs: &Vec<BlobWithSender>,
+    ) -> Result<Self, ProofGenerationError> {
+        let square_size = block.square_size();
+        let ods_width = square_size / 2;
+
+        // Pfb positions are counted from the start of the pfb namespace, so convert them into
+        // indices in the extended data square
+        let namespace_start = if relevant_txs.is_empty() {
+            0
+        } else {
+            block
+                .pfb_namespace_start()
+                .ok_or(ProofGenerationError::MissingPfbNamespace)?
+        };
+        let eds_idx = |namespace_idx: usize| {
+            let ods_idx = namespace_start + namespace_idx;
+            (ods_idx / ods_width) * square_size + ods_idx % ods_width
+        };
+
+        // Several blobs may share a commitment (if identical data was submitted more than once), so
+        // match each tx to the first unused pfb with the right namespace, commitment *and* sender
+        let blob_pfbs: Vec<_> = block.blob_pfbs().collect();
+        let mut used = vec![false; blob_pfbs.len()];
 
-        // Extract (and clone) the position of each transaction
+        let mut needed_shares = BTreeSet::new();
+        let mut txs = Vec::with_capacity(relevant_txs.len());
         for tx in relevant_txs.iter() {
-            let commitment = recreate_commitment(block.square_size(), BlobRef::with(&tx.blob.0))
-                .expect("commitment is valid");
-
-            let (_, position) = block
-                .relevant_pfbs
-                .get(&commitment[..])
-                .expect("commitment must exist in map");
-            needed_tx_shares.push(position.clone());
+            let commitment = recreate_commitment(square_size, BlobRef::with(&tx.blob.0))
+                .map_err(ProofGenerationError::InvalidBlob)?;
+
+            let entry_idx = blob_pfbs
+                .iter()
+                .zip(used.iter())
+                .position(|((relevant_pfb, blob_idx), used)| {
+                    !used
+                        && relevant_pfb.pfb.namespace_ids[*blob_idx][..]
+                            == tx.metadata.namespace.0[..]
+                        && relevant_pfb
+                            .pfb
+                            .share_commitments
+                            .get(*blob_idx)
+                            .map_or(false, |expected| expected[..] == commitment[..])
+                        && relevant_pfb.pfb.signer.as_bytes() == tx.sender.as_ref()
+                })
+                .ok_or(ProofGenerationError::MissingPfb(commitment))?;
+            used[entry_idx] = true;
+
+            let (relevant_pfb, blob_idx) = blob_pfbs[entry_idx];
+            let position = &relevant_pfb.position;
+            needed_shares.extend(position.share_range.clone().map(eds_idx));
+            txs.push(EtxLocation {
+                start_share_idx: eds_idx(position.share_range.start),
+                start_offset: position.start_offset,
+                blob_idx,
+            });
         }
 
-        let mut needed_tx_shares = needed_tx_shares.into_iter().peekable();
-        let mut current_tx_proof: EtxProof = EtxProof { proof: Vec::new() };
-        let mut tx_proofs: Vec<EtxProof> = Vec::with_capacity(relevant_txs.len());
-
-        for (row_idx, row) in block.pfb_rows.iter().enumerate() {
-            let mut nmt = row.merklized();
-            while let Some(next_needed_share) = needed_tx_shares.peek_mut() {
-                // If the next needed share falls in this row
-                let row_start_idx = block.square_size() * row_idx;
-                let start_column_number = next_needed_share.share_range.start - row_start_idx;
-                if start_column_number < block.square_size() {
-                    let end_column_number = next_needed_share.share_range.end - row_start_idx;
-                    if end_column_number <= block.square_size() {
-                        let (shares, proof) =
-                            nmt.get_range_with_proof(start_column_number..end_column_number);
-
-                        current_tx_proof.proof.push(EtxRangeProof {
-                            shares,
-                            proof,
-                            start_offset: next_needed_share.start_offset,
-                            start_share_idx: next_needed_share.share_range.start,
-                        });
-                        tx_proofs.push(current_tx_proof);
-                        current_tx_proof = EtxProof { proof: Vec::new() };
-                        let _ = needed_tx_shares.next();
-                    } else {
-                        let (shares, proof) =
-                            nmt.get_range_with_proof(start_column_number..block.square_size());
-
-                        current_tx_proof.proof.push(EtxRangeProof {
-                            shares,
-                            proof,
-                            start_offset: next_needed_share.start_offset,
-                            start_share_idx: next_needed_share.share_range.start,
-                        });
-                        next_needed_share.share_range.start = block.square_size() * (row_idx + 1);
-                        next_needed_share.start_offset = 0;
-
-                        break;
-                    }
-                } else {
-                    break;
-                }
+        // Prove each run of consecutive needed shares with a single range proof. Since parity shares
+        // are never needed, a run can't cross a row boundary. The block caches its row trees, so each
+        // row is merklized at most once, no matter the order of the txs.
+        block.merklize_rows();
+        let mut ranges = Vec::new();
+        let mut needed_shares = needed_shares.into_iter().peekable();
+        while let Some(start) = needed_shares.next() {
+            let mut end = start + 1;
+            while needed_shares.next_if_eq(&end).is_some() {
+                end += 1;
             }
+
+            let row_idx = start / square_size;
+            let row = block
+                .pfb_row(row_idx)
+                .ok_or(ProofGenerationError::MissingRow(row_idx))?;
+            let start_column = start % square_size;
+            let (shares, proof) = block.with_row_tree(row, |nmt| {
+                nmt.get_range_with_proof(start_column..start_column + (end - start))
+            });
+            ranges.push(EtxRangeProof {
+                shares,
+                proof,
+                start_share_idx: start,
+            });
         }
-        Self(tx_proofs)
+        let proof = EtxProof { ranges, txs };
+        telemetry::record_proof_size("correctness", &proof);
+        Ok(Self(proof))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use sovereign_sdk::da::DaVerifier;
+
+    use nmt_rs::NamespaceId;
+
+    use super::{CompletenessProof, CorrectnessProof, ProofGenerationError};
+    use crate::{
+        shares::Blob,
+        test_utils::{single_blob_block, sparse_shares, BlockBuilder, TestBlob, TEST_NAMESPACE},
+        types::ValidationError,
+        verifier::{address::CelestiaAddress, CelestiaVerifier, RollupParams},
+        BlobWithSender,
+    };
+
+    /// A namespace which sorts before the rollup namespace
+    const OTHER_NAMESPACE: NamespaceId = NamespaceId(*b"sov-aaaa");
+    /// A second rollup namespace, which sorts after the rollup namespace
+    const FORCED_NAMESPACE: NamespaceId = NamespaceId(*b"sov-zzzz");
+
+    fn verifier_for(namespaces: &BTreeSet<NamespaceId>) -> CelestiaVerifier {
+        CelestiaVerifier::new(RollupParams {
+            namespaces: namespaces.clone(),
+        })
+    }
+
+    /// A verifier for the test rollup's namespace
+    fn test_verifier() -> CelestiaVerifier {
+        verifier_for(&BTreeSet::from([TEST_NAMESPACE]))
+    }
+
+    /// Proves `txs` against the block and checks the proofs with [`test_verifier`]
+    fn check(
+        block: &crate::types::FilteredCelestiaBlock,
+        txs: &Vec<BlobWithSender>,
+    ) -> Result<(), ValidationError> {
+        let etx_proof = CorrectnessProof::for_block(block, txs).unwrap().0;
+        let completeness_proof = CompletenessProof::from_filtered_block(block).0;
+        test_verifier().verify_relevant_tx_list(&block.header, txs, etx_proof, completeness_proof)
+    }
+
+    fn verify(block: &crate::types::FilteredCelestiaBlock, txs: &Vec<BlobWithSender>) {
+        check(block, txs).expect("proofs must be valid");
+    }
+
+    /// Builds a block in which the first relevant pfb spans three rows of the square, and the pfb
+    /// namespace starts in the middle of a row
+    fn multi_row_pfb_block() -> (crate::types::FilteredCelestiaBlock, Vec<BlobWithSender>) {
+        // A very long signer stretches the pfb across ~9 shares
+        let long_signer = "c".repeat(4500);
+        BlockBuilder::new(4)
+            .with_tx_shares(2)
+            .with_blob(TestBlob::rollup(&long_signer, vec![1; 10]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![2; 10]))
+            .build(TEST_NAMESPACE)
+    }
+
+    #[test]
+    fn test_shared_etx_shares_are_proven_once() {
+        // Both pfbs are small enough to fit in the first share of the pfb namespace
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![1; 10]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![2; 10]))
+            .build(TEST_NAMESPACE);
+
+        let etx_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        assert_eq!(etx_proof.ranges.len(), 1);
+        assert_eq!(etx_proof.ranges[0].shares.len(), 1);
+        assert_eq!(etx_proof.txs.len(), 2);
+        verify(&block, &txs);
+    }
+
+    #[test]
+    fn test_pfb_spanning_three_rows() {
+        let (block, txs) = multi_row_pfb_block();
+        let etx_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+
+        let square_size = block.square_size();
+        let mut rows: Vec<usize> = etx_proof
+            .ranges
+            .iter()
+            .map(|range| range.start_share_idx / square_size)
+            .collect();
+        rows.dedup();
+        assert_eq!(rows, vec![0, 1, 2]);
+        // The pfb namespace starts after the two ordinary tx shares
+        assert_eq!(etx_proof.txs[0].start_share_idx, 2);
+
+        verify(&block, &txs);
+    }
+
+    #[test]
+    fn test_out_of_order_txs() {
+        let (block, txs) = multi_row_pfb_block();
+        let in_order = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+
+        let reversed: Vec<BlobWithSender> = txs.iter().rev().cloned().collect();
+        let out_of_order = CorrectnessProof::for_block(&block, &reversed).unwrap().0;
+
+        assert_eq!(in_order.ranges, out_of_order.ranges);
+        assert_eq!(
+            in_order.txs,
+            out_of_order.txs.into_iter().rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_missing_pfb() {
+        let (block, mut txs) = multi_row_pfb_block();
+        let shares = sparse_shares(TEST_NAMESPACE, b"never submitted")
+            .into_iter()
+            .map(|share| crate::shares::Share::new(share.into()))
+            .collect();
+        txs.push(BlobWithSender {
+            blob: Blob(shares),
+            sender: CelestiaAddress(b"celestia1mallory".to_vec()),
+            metadata: txs[0].metadata.clone(),
+        });
+
+        assert!(matches!(
+            CorrectnessProof::for_block(&block, &txs),
+            Err(ProofGenerationError::MissingPfb(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_blobs_keep_their_senders() {
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::rollup("celestia1alice", vec![3; 100]))
+            .with_blob(TestBlob::rollup("celestia1bob", vec![3; 100]))
+            .build(TEST_NAMESPACE);
+        assert_eq!(block.relevant_pfbs.len(), 2);
+        verify(&block, &txs);
+
+        // Swapping the senders of the identical blobs must be detected
+        let mut swapped = txs.clone();
+        swapped[0].sender = txs[1].sender.clone();
+        swapped[1].sender = txs[0].sender.clone();
+        assert!(check(&block, &swapped).is_err());
+    }
+
+    #[test]
+    fn test_blob_metadata() {
+        let (block, txs) = multi_row_pfb_block();
+        // The second blob directly follows the first in the rollup namespace
+        assert_eq!(
+            txs[1].metadata.start_share_idx,
+            txs[0].metadata.start_share_idx + txs[0].blob.0.len()
+        );
+        assert_ne!(txs[0].metadata.pfb_tx_hash, txs[1].metadata.pfb_tx_hash);
+        verify(&block, &txs);
+
+        let mut tampered = txs.clone();
+        tampered[1].metadata.column += 1;
+        assert_eq!(
+            check(&block, &tampered),
+            Err(ValidationError::InvalidBlobMetadata)
+        );
+    }
+
+    #[test]
+    fn test_empty_namespace_in_straddling_row() {
+        // The other blob shares its row with tail padding, so the row's range contains the rollup namespace
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::new(
+                OTHER_NAMESPACE,
+                "celestia1alice",
+                vec![1; 10],
+            ))
+            .build(TEST_NAMESPACE);
+        assert!(txs.is_empty());
+        assert!(block.header.dah.absence_row(TEST_NAMESPACE).is_none());
+        assert_eq!(block.rollup_data[0].rows.len(), 1);
+        verify(&block, &txs);
+    }
+
+    #[test]
+    fn test_empty_namespace_between_rows() {
+        // The pfb and the other blob fill the first row exactly, so the rollup namespace falls between rows
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::new(
+                OTHER_NAMESPACE,
+                "celestia1alice",
+                vec![1; 1100],
+            ))
+            .build(TEST_NAMESPACE);
+        assert_eq!(block.header.dah.absence_row(TEST_NAMESPACE), Some(1));
+        assert_eq!(block.rollup_data[0].rows.len(), 1);
+        verify(&block, &txs);
+
+        let etx_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        assert_eq!(
+            test_verifier().verify_relevant_tx_list(&block.header, &txs, etx_proof, vec![]),
+            Err(ValidationError::InvalidRowProof)
+        );
+    }
+
+    #[test]
+    fn test_tampered_presence_proof() {
+        let (block, txs) = single_blob_block(4, vec![1; 1000]);
+        let etx_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        let mut completeness_proof = CompletenessProof::from_filtered_block(&block).0;
+        completeness_proof[0].leaves[0][100] ^= 1;
+        assert_eq!(
+            test_verifier().verify_relevant_tx_list(
+                &block.header,
+                &txs,
+                etx_proof,
+                completeness_proof
+            ),
+            Err(ValidationError::InvalidRowProof)
+        );
+    }
+
+    #[test]
+    fn test_multiple_rollup_namespaces() {
+        let namespaces = BTreeSet::from([TEST_NAMESPACE, FORCED_NAMESPACE]);
+        let (block, txs) = BlockBuilder::new(4)
+            .with_blob(TestBlob::new(
+                FORCED_NAMESPACE,
+                "celestia1alice",
+                vec![1; 10],
+            ))
+            .with_blob(TestBlob::new(OTHER_NAMESPACE, "celestia1bob", vec![2; 10]))
+            .with_blob(TestBlob::rollup("celestia1carol", vec![3; 10]))
+            .build_for_namespaces(&namespaces);
+        let tx_namespaces: Vec<_> = txs.iter().map(|tx| tx.metadata.namespace).collect();
+        assert_eq!(tx_namespaces, vec![TEST_NAMESPACE, FORCED_NAMESPACE]);
+
+        let etx_proof = CorrectnessProof::for_block(&block, &txs).unwrap().0;
+        let completeness_proof = CompletenessProof::from_filtered_block(&block).0;
+        let verifier = verifier_for(&namespaces);
+        verifier
+            .verify_relevant_tx_list(
+                &block.header,
+                &txs,
+                etx_proof.clone(),
+                completeness_proof.clone(),
+            )
+            .expect("proofs must be valid");
+
+        // Omitting the blobs of one namespace must be detected
+        let mut partial_proof = completeness_proof.clone();
+        partial_proof.retain(|row_proof| row_proof.namespace == TEST_NAMESPACE);
+        assert_eq!(
+            verifier.verify_relevant_tx_list(&block.header, &txs, etx_proof.clone(), partial_proof),
+            Err(ValidationError::InvalidRowProof)
+        );
+
+        // A verifier which only reads the batch namespace rejects proofs for the other namespace
+        assert!(test_verifier()
+            .verify_relevant_tx_list(&block.header, &txs, etx_proof, completeness_proof)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cached_row_trees_give_the_same_proofs() {
+        let (block, txs) = multi_row_pfb_block();
+        let fresh = block.clone();
+        // Build the proofs once to populate the cache, then compare against a block without cached trees
+        CompletenessProof::from_filtered_block(&block);
+        assert_eq!(
+            CompletenessProof::from_filtered_block(&block),
+            CompletenessProof::from_filtered_block(&fresh)
+        );
+        assert_eq!(
+            CorrectnessProof::for_block(&block, &txs).unwrap().0,
+            CorrectnessProof::for_block(&fresh, &txs).unwrap().0
+        );
+        assert_eq!(block, fresh);
     }
 }